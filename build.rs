@@ -1,14 +1,25 @@
 use std::path::Path;
 use std::{env, fs, io, process};
 
-const COMMANDS: [&str; 7] = [
+const COMMANDS: [&str; 18] = [
     "krunvm",
+    "krunvm-apply",
     "krunvm-changevm",
+    "krunvm-commit",
     "krunvm-create",
     "krunvm-config",
+    "krunvm-context",
     "krunvm-delete",
+    "krunvm-exec",
+    "krunvm-export-config",
+    "krunvm-images",
+    "krunvm-import-config",
     "krunvm-list",
+    "krunvm-logs",
+    "krunvm-pull",
+    "krunvm-rmi",
     "krunvm-start",
+    "krunvm-stop",
 ];
 
 fn main() {
@@ -0,0 +1,124 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Protocol spoken between `krunvm exec` and the agent running inside the
+//! guest, over a vsock connection libkrun exposes as a unix socket on the
+//! host.
+//!
+//! Each message is a frame made of its kind, the length of its payload as
+//! a big endian u32, and the payload itself. The host opens the
+//! connection with a `REQUEST`, then both ends exchange `STDIN`, `STDOUT`
+//! and `STDERR` frames until the agent sends `EXIT` with the exit code of
+//! the command. An empty `STDIN` frame closes the standard input of the
+//! command.
+//!
+//! The host may instead open the connection with `SHUTDOWN`, asking the
+//! agent to terminate the command of the VM with SIGTERM, so the guest
+//! powers off once it exits.
+//!
+//! This module is shared with the agent binary, so it must not depend on
+//! the rest of the crate.
+
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// vsock port the agent listens on inside the guest.
+pub const AGENT_PORT: u32 = 1024;
+
+/// Path of the agent inside the guest.
+pub const AGENT_GUEST_PATH: &str = "/.krunvm-agent";
+
+pub const REQUEST: u8 = 0;
+pub const STDIN: u8 = 1;
+pub const STDOUT: u8 = 2;
+pub const STDERR: u8 = 3;
+pub const EXIT: u8 = 4;
+pub const SHUTDOWN: u8 = 5;
+
+/// Largest payload accepted in a frame, so neither end can make the other
+/// allocate arbitrary amounts of memory.
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// Command to be run by the agent.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExecRequest {
+    /// Program and arguments.
+    pub command: Vec<String>,
+    /// Variables added to the environment of the agent, as "KEY=VALUE".
+    pub env: Vec<String>,
+    /// Working directory, empty to keep the one of the agent.
+    pub workdir: String,
+}
+
+pub fn write_frame(writer: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "frame too large"));
+    }
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "frame too large"))?;
+
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Reads the next frame, returning `None` if the connection was closed
+/// between frames.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 5];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes exceeds the limit of {}",
+                len, MAX_FRAME_SIZE
+            ),
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((header[0], payload)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_written_frames() {
+        let mut data = Vec::new();
+        write_frame(&mut data, STDOUT, b"hello").unwrap();
+        write_frame(&mut data, EXIT, &[]).unwrap();
+
+        let mut reader = &data[..];
+        assert_eq!(
+            read_frame(&mut reader).unwrap(),
+            Some((STDOUT, b"hello".to_vec()))
+        );
+        assert_eq!(read_frame(&mut reader).unwrap(), Some((EXIT, Vec::new())));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let payload = vec![0u8; MAX_FRAME_SIZE + 1];
+        assert!(write_frame(&mut Vec::new(), STDIN, &payload).is_err());
+
+        let mut data = vec![STDIN];
+        data.extend_from_slice(&u32::MAX.to_be_bytes());
+        let err = read_frame(&mut &data[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,488 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Instant;
+
+use serde_derive::Deserialize;
+
+use super::{CommitConfig, ContainerBackend, ImageSummary};
+use crate::trace;
+use crate::{BuildahError, KrunvmConfig, KrunvmError};
+
+enum BuildahCommand {
+    From,
+    Inspect,
+    Mount,
+    Unmount,
+    Remove,
+    Commit,
+    Images,
+    Pull,
+    RemoveImage,
+}
+
+/// Messages buildah prints when asked about a container that doesn't
+/// exist.
+const CONTAINER_NOT_FOUND: [&str; 2] = ["container not known", "no such container"];
+
+/// An entry of the output of `buildah images --json`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct BuildahImage {
+    id: String,
+    names: Option<Vec<String>>,
+    size: String,
+}
+
+/// Quotes `value` as a single word of a Dockerfile instruction, with no
+/// variable expanded.
+fn dockerfile_word(value: &str) -> Result<String, KrunvmError> {
+    if value.contains('\n') {
+        return Err(KrunvmError::InvalidInput(format!(
+            "{:?} can't be committed, as it spans multiple lines",
+            value
+        )));
+    }
+    let mut word = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$') {
+            word.push('\\');
+        }
+        word.push(c);
+    }
+    word.push('"');
+    Ok(word)
+}
+
+/// Returns the Dockerfile instructions applying `config` to a committed
+/// image, as taken by `buildah commit --change`.
+fn commit_changes(config: &CommitConfig) -> Result<Vec<String>, KrunvmError> {
+    let mut changes = Vec::new();
+    for env in config.env.iter() {
+        let (key, value) = env.split_once('=').unwrap_or((env, ""));
+        changes.push(format!("ENV {}={}", key, dockerfile_word(value)?));
+    }
+    if let Some(ref workdir) = config.workdir {
+        changes.push(format!("WORKDIR {}", dockerfile_word(workdir)?));
+    }
+    if let Some(ref command) = config.command {
+        // An empty entrypoint clears the image's one, and a JSON array
+        // keeps the arguments from being split again.
+        changes.push("ENTRYPOINT []".to_string());
+        changes.push(format!("CMD {}", serde_json::to_string(command).unwrap()));
+    }
+    for port in config.ports.iter() {
+        changes.push(format!("EXPOSE {}", port));
+    }
+    Ok(changes)
+}
+
+/// Backend delegating the management of the containers to buildah.
+pub struct BuildahBackend {
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    storage_volume: String,
+    // On macOS, the storage lives in `storage_volume` and the registries
+    // and policy files are the ones bundled with krunvm.
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    root: String,
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    runroot: String,
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    storage_driver: String,
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    registries_conf: String,
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    signature_policy: String,
+}
+
+impl BuildahBackend {
+    pub fn new(cfg: &KrunvmConfig) -> BuildahBackend {
+        BuildahBackend {
+            storage_volume: cfg.storage_volume.clone(),
+            root: cfg.buildah_root.clone(),
+            runroot: cfg.buildah_runroot.clone(),
+            storage_driver: cfg.buildah_storage_driver.clone(),
+            registries_conf: cfg.buildah_registries_conf.clone(),
+            signature_policy: cfg.buildah_signature_policy.clone(),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn get_args(&self, cmd: BuildahCommand) -> Vec<String> {
+        let mut args = Vec::new();
+
+        let global_opts = [
+            ("--root", &self.root),
+            ("--runroot", &self.runroot),
+            ("--storage-driver", &self.storage_driver),
+            ("--registries-conf", &self.registries_conf),
+        ];
+        for (opt, value) in global_opts {
+            if !value.is_empty() {
+                args.push(opt.to_string());
+                args.push(value.clone());
+            }
+        }
+
+        let uses_policy = matches!(
+            cmd,
+            BuildahCommand::From | BuildahCommand::Pull | BuildahCommand::Commit
+        );
+
+        match cmd {
+            BuildahCommand::From => args.push("from".to_string()),
+            BuildahCommand::Inspect => args.push("inspect".to_string()),
+            BuildahCommand::Mount => args.push("mount".to_string()),
+            BuildahCommand::Unmount => args.push("umount".to_string()),
+            BuildahCommand::Remove => args.push("rm".to_string()),
+            BuildahCommand::Commit => args.push("commit".to_string()),
+            BuildahCommand::Images => {
+                args.push("images".to_string());
+                args.push("--json".to_string());
+            }
+            BuildahCommand::Pull => args.push("pull".to_string()),
+            BuildahCommand::RemoveImage => args.push("rmi".to_string()),
+        }
+
+        if uses_policy && !self.signature_policy.is_empty() {
+            args.push("--signature-policy".to_string());
+            args.push(self.signature_policy.clone());
+        }
+        args
+    }
+
+    #[cfg(target_os = "macos")]
+    fn get_args(&self, cmd: BuildahCommand) -> Vec<String> {
+        let mut hbpath = std::env::current_exe().unwrap();
+        hbpath.pop();
+        hbpath.pop();
+        let hbpath = hbpath.as_path().display();
+        let policy_json = format!("{}/etc/containers/policy.json", hbpath);
+        let registries_json = format!("{}/etc/containers/registries.conf", hbpath);
+        let storage_root = format!("{}/root", self.storage_volume);
+        let storage_runroot = format!("{}/runroot", self.storage_volume);
+
+        let mut args = vec![
+            "--root".to_string(),
+            storage_root,
+            "--runroot".to_string(),
+            storage_runroot,
+        ];
+
+        match cmd {
+            BuildahCommand::From => {
+                args.push("--signature-policy".to_string());
+                args.push(policy_json);
+                args.push("--registries-conf".to_string());
+                args.push(registries_json);
+
+                args.push("from".to_string());
+                args.push("--os".to_string());
+                args.push("linux".to_string());
+            }
+            BuildahCommand::Inspect => {
+                args.push("inspect".to_string());
+            }
+            BuildahCommand::Mount => {
+                args.push("mount".to_string());
+            }
+            BuildahCommand::Unmount => {
+                args.push("umount".to_string());
+            }
+            BuildahCommand::Remove => {
+                args.push("rm".to_string());
+            }
+            BuildahCommand::Commit => {
+                args.push("commit".to_string());
+            }
+            BuildahCommand::Images => {
+                args.push("images".to_string());
+                args.push("--json".to_string());
+            }
+            BuildahCommand::Pull => {
+                args.push("--signature-policy".to_string());
+                args.push(policy_json);
+                args.push("--registries-conf".to_string());
+                args.push(registries_json);
+
+                args.push("pull".to_string());
+                args.push("--os".to_string());
+                args.push("linux".to_string());
+            }
+            BuildahCommand::RemoveImage => {
+                args.push("rmi".to_string());
+            }
+        }
+        args
+    }
+
+    /// Runs buildah with `args`, returning its standard output.
+    /// `container` is the container the command operates on, if any, to
+    /// tell apart failures caused by it not existing.
+    fn run(&self, args: Vec<String>, container: Option<&str>) -> Result<String, KrunvmError> {
+        self.run_with_progress(args, container, false)
+    }
+
+    /// Like `run`, but with `progress` buildah's stderr is also forwarded
+    /// to ours as it arrives, for commands that may take a while.
+    fn run_with_progress(
+        &self,
+        args: Vec<String>,
+        container: Option<&str>,
+        progress: bool,
+    ) -> Result<String, KrunvmError> {
+        let command = format!("buildah {}", args.join(" "));
+        let started = Instant::now();
+
+        let mut child = Command::new("buildah")
+            .args(&args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(spawn_error)?;
+
+        let mut child_stderr = child.stderr.take().unwrap();
+        let stderr_reader = thread::spawn(move || {
+            let mut captured = Vec::new();
+            let mut buf = [0u8; 4096];
+            while let Ok(len) = child_stderr.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+                if progress {
+                    let _ = io::stderr().write_all(&buf[..len]);
+                }
+                captured.extend_from_slice(&buf[..len]);
+            }
+            captured
+        });
+
+        let mut stdout = Vec::new();
+        let status = child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_end(&mut stdout)
+            .and_then(|_| child.wait())
+            .map_err(|err| KrunvmError::Buildah(BuildahError::Spawn(err)))?;
+        let stderr = stderr_reader.join().unwrap_or_default();
+        let stderr = String::from_utf8_lossy(&stderr).trim().to_string();
+
+        trace::event(
+            trace::DEBUG,
+            "buildah",
+            &[
+                ("command", &command),
+                ("status", &status.code().unwrap_or(-1)),
+                ("elapsed_ms", &started.elapsed().as_millis()),
+            ],
+        );
+        trace::event(
+            trace::TRACE,
+            "buildah-output",
+            &[
+                ("stdout", &String::from_utf8_lossy(&stdout).trim()),
+                ("stderr", &stderr),
+            ],
+        );
+
+        if !status.success() {
+            if let Some(container) = container {
+                if CONTAINER_NOT_FOUND.iter().any(|msg| stderr.contains(msg)) {
+                    return Err(KrunvmError::ContainerNotFound(container.to_string()));
+                }
+            }
+            return Err(KrunvmError::Buildah(BuildahError::Failed {
+                command,
+                code: status.code().unwrap_or(-1),
+                stderr,
+            }));
+        }
+
+        String::from_utf8(stdout).map_err(|err| {
+            KrunvmError::Buildah(BuildahError::Output(format!(
+                "Failed to parse the output of `{}`: {}",
+                command, err
+            )))
+        })
+    }
+}
+
+impl ContainerBackend for BuildahBackend {
+    fn from(&self, image: &str, arch: Option<&str>) -> Result<String, KrunvmError> {
+        let mut args = self.get_args(BuildahCommand::From);
+        if let Some(arch) = arch {
+            args.push("--arch".to_string());
+            args.push(arch.to_string());
+        }
+        args.push(image.to_string());
+
+        Ok(self.run_with_progress(args, None, true)?.trim().to_string())
+    }
+
+    fn mount(&self, container: &str) -> Result<String, KrunvmError> {
+        let mut args = self.get_args(BuildahCommand::Mount);
+        args.push(container.to_string());
+
+        let rootfs = self.run(args, Some(container))?.trim().to_string();
+
+        #[cfg(target_os = "macos")]
+        fix_root_mode(&rootfs)?;
+
+        Ok(rootfs)
+    }
+
+    fn umount(&self, container: &str) -> Result<(), KrunvmError> {
+        let mut args = self.get_args(BuildahCommand::Unmount);
+        args.push(container.to_string());
+
+        self.run(args, Some(container)).map(|_| ())
+    }
+
+    fn rm(&self, container: &str) -> Result<(), KrunvmError> {
+        let mut args = self.get_args(BuildahCommand::Remove);
+        args.push(container.to_string());
+
+        self.run(args, Some(container)).map(|_| ())
+    }
+
+    fn inspect(&self, name: &str) -> Result<String, KrunvmError> {
+        let mut args = self.get_args(BuildahCommand::Inspect);
+        args.push(name.to_string());
+
+        self.run(args, Some(name))
+    }
+
+    fn commit(
+        &self,
+        container: &str,
+        image: &str,
+        config: &CommitConfig,
+    ) -> Result<(), KrunvmError> {
+        // The configuration only applies to the committed image, leaving
+        // the one of the container alone.
+        let mut args = self.get_args(BuildahCommand::Commit);
+        for change in commit_changes(config)? {
+            args.push("--change".to_string());
+            args.push(change);
+        }
+        args.push(container.to_string());
+        args.push(image.to_string());
+
+        self.run(args, Some(container)).map(|_| ())
+    }
+
+    fn images(&self) -> Result<Vec<ImageSummary>, KrunvmError> {
+        let output = self.run(self.get_args(BuildahCommand::Images), None)?;
+        // Older versions of buildah print nothing at all without images.
+        if output.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let images: Vec<BuildahImage> = serde_json::from_str(&output).map_err(|err| {
+            KrunvmError::Buildah(BuildahError::Output(format!(
+                "Failed to parse `buildah images` output: {}",
+                err
+            )))
+        })?;
+        Ok(images
+            .into_iter()
+            .map(|image| ImageSummary {
+                id: image.id,
+                names: image.names.unwrap_or_default(),
+                size: image.size,
+            })
+            .collect())
+    }
+
+    fn pull(&self, image: &str) -> Result<String, KrunvmError> {
+        let mut args = self.get_args(BuildahCommand::Pull);
+        args.push(image.to_string());
+
+        // The ID of the image is printed last, after the progress report.
+        let output = self.run_with_progress(args, None, true)?;
+        Ok(output.lines().last().unwrap_or_default().trim().to_string())
+    }
+
+    fn rmi(&self, image: &str, force: bool) -> Result<(), KrunvmError> {
+        let mut args = self.get_args(BuildahCommand::RemoveImage);
+        if force {
+            args.push("--force".to_string());
+        }
+        args.push(image.to_string());
+
+        self.run(args, None).map(|_| ())
+    }
+}
+
+fn spawn_error(err: io::Error) -> KrunvmError {
+    if err.kind() == io::ErrorKind::NotFound {
+        KrunvmError::Buildah(BuildahError::NotInstalled)
+    } else {
+        KrunvmError::Buildah(BuildahError::Spawn(err))
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn fix_root_mode(rootfs: &str) -> Result<(), KrunvmError> {
+    let mut args = vec!["-w", "user.containers.override_stat", "0:0:0555"];
+    args.push(rootfs);
+
+    let output = match Command::new("xattr")
+        .args(&args)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => return Err(KrunvmError::Io("executing xattr".to_string(), err)),
+    };
+
+    let exit_code = output.status.code().unwrap_or(-1);
+    if exit_code != 0 {
+        return Err(KrunvmError::Io(
+            "setting the rootfs mode".to_string(),
+            std::io::Error::other(format!("xattr returned an error: {}", exit_code)),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_commit_config_as_changes() {
+        let config = CommitConfig {
+            env: vec!["A=1".to_string(), "B=\"$HOME\" \\o/".to_string()],
+            workdir: Some("/srv/my app".to_string()),
+            command: Some(vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                "a b".to_string(),
+            ]),
+            ports: vec!["80/tcp".to_string()],
+        };
+        assert_eq!(
+            commit_changes(&config).unwrap(),
+            vec![
+                r#"ENV A="1""#,
+                r#"ENV B="\"\$HOME\" \\o/""#,
+                r#"WORKDIR "/srv/my app""#,
+                "ENTRYPOINT []",
+                r#"CMD ["/bin/sh","-c","a b"]"#,
+                "EXPOSE 80/tcp",
+            ]
+        );
+
+        let config = CommitConfig {
+            env: vec!["A=1\n2".to_string()],
+            ..Default::default()
+        };
+        assert!(commit_changes(&config).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! In-memory backend for tests. The root filesystems of its containers are
+//! directories under a temporary one.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_json::json;
+
+use super::{Backends, CommitConfig, ContainerBackend, ImageSummary};
+use crate::image::find_images;
+use crate::{KrunvmConfig, KrunvmError};
+
+/// Directory removed, along with its contents, when dropped.
+pub(crate) struct TestDir(PathBuf);
+
+impl TestDir {
+    pub(crate) fn new() -> TestDir {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "krunvm-test-{}-{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Returns the default configuration, stored in `dir`.
+pub(crate) fn test_config(dir: &TestDir) -> KrunvmConfig {
+    KrunvmConfig {
+        config_dir: dir.path().join("config"),
+        ..Default::default()
+    }
+}
+
+/// An image known to the fake backend.
+pub(crate) struct FakeImage {
+    pub id: String,
+    pub names: Vec<String>,
+    pub os: String,
+}
+
+/// A container created by the fake backend.
+pub(crate) struct FakeContainer {
+    pub image_id: String,
+    pub mounted: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct FakeState {
+    pub images: Vec<FakeImage>,
+    pub containers: BTreeMap<String, FakeContainer>,
+    /// Operation, such as "mount", failing on every call.
+    pub failing: Option<&'static str>,
+    /// Images committed, along with the sorted names of the files in the
+    /// root directory of their container at the time.
+    pub commits: Vec<(String, Vec<String>)>,
+}
+
+/// Backend keeping its images and containers in memory. It's returned for
+/// every backend name, and clones share their state.
+#[derive(Clone)]
+pub(crate) struct FakeBackend {
+    pub state: Rc<RefCell<FakeState>>,
+    root: Rc<TestDir>,
+}
+
+impl FakeBackend {
+    pub(crate) fn new() -> FakeBackend {
+        FakeBackend {
+            state: Rc::new(RefCell::new(FakeState::default())),
+            root: Rc::new(TestDir::new()),
+        }
+    }
+
+    pub(crate) fn add_image(&self, id: &str, name: &str) {
+        self.state.borrow_mut().images.push(FakeImage {
+            id: id.to_string(),
+            names: vec![name.to_string()],
+            os: "linux".to_string(),
+        });
+    }
+
+    /// Returns the root filesystem of `container`, whether it's mounted
+    /// or not.
+    pub(crate) fn rootfs(&self, container: &str) -> PathBuf {
+        self.root.path().join(container)
+    }
+
+    fn check(&self, op: &str) -> Result<(), KrunvmError> {
+        if self.state.borrow().failing == Some(op) {
+            return Err(KrunvmError::InvalidInput(format!("{} failed", op)));
+        }
+        Ok(())
+    }
+
+    fn summary(image: &FakeImage) -> ImageSummary {
+        ImageSummary {
+            id: image.id.clone(),
+            names: image.names.clone(),
+            size: "1 MB".to_string(),
+        }
+    }
+
+    /// Returns the IDs of the images `reference` points to.
+    fn find(&self, reference: &str) -> Vec<String> {
+        let images: Vec<ImageSummary> = self
+            .state
+            .borrow()
+            .images
+            .iter()
+            .map(FakeBackend::summary)
+            .collect();
+        find_images(&images, reference)
+            .into_iter()
+            .map(|image| image.id.clone())
+            .collect()
+    }
+}
+
+impl Backends for FakeBackend {
+    fn get(
+        &self,
+        _cfg: &KrunvmConfig,
+        _name: &str,
+    ) -> Result<Box<dyn ContainerBackend>, KrunvmError> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl ContainerBackend for FakeBackend {
+    fn from(&self, image: &str, _arch: Option<&str>) -> Result<String, KrunvmError> {
+        self.check("from")?;
+        let image_id = self
+            .find(image)
+            .into_iter()
+            .next()
+            .ok_or_else(|| KrunvmError::InvalidInput(format!("image {} not known", image)))?;
+        let mut state = self.state.borrow_mut();
+
+        let container = format!("container-{}", state.containers.len());
+        fs::create_dir_all(self.rootfs(&container)).unwrap();
+        state.containers.insert(
+            container.clone(),
+            FakeContainer {
+                image_id,
+                mounted: false,
+            },
+        );
+        Ok(container)
+    }
+
+    fn mount(&self, container: &str) -> Result<String, KrunvmError> {
+        self.check("mount")?;
+        let mut state = self.state.borrow_mut();
+        let entry = state
+            .containers
+            .get_mut(container)
+            .ok_or_else(|| KrunvmError::ContainerNotFound(container.to_string()))?;
+        entry.mounted = true;
+        Ok(self.rootfs(container).to_string_lossy().into_owned())
+    }
+
+    fn umount(&self, container: &str) -> Result<(), KrunvmError> {
+        self.check("umount")?;
+        let mut state = self.state.borrow_mut();
+        let entry = state
+            .containers
+            .get_mut(container)
+            .ok_or_else(|| KrunvmError::ContainerNotFound(container.to_string()))?;
+        entry.mounted = false;
+        Ok(())
+    }
+
+    fn rm(&self, container: &str) -> Result<(), KrunvmError> {
+        self.check("rm")?;
+        self.state
+            .borrow_mut()
+            .containers
+            .remove(container)
+            .ok_or_else(|| KrunvmError::ContainerNotFound(container.to_string()))?;
+        let _ = fs::remove_dir_all(self.rootfs(container));
+        Ok(())
+    }
+
+    fn inspect(&self, name: &str) -> Result<String, KrunvmError> {
+        self.check("inspect")?;
+        let found = self.find(name);
+        let state = self.state.borrow();
+        let (container, image_id) = match state.containers.get(name) {
+            Some(container) => (name, container.image_id.as_str()),
+            None => ("", found.first().map_or("", |id| id.as_str())),
+        };
+        let image = state
+            .images
+            .iter()
+            .find(|image| image.id == image_id)
+            .ok_or_else(|| KrunvmError::InvalidInput(format!("{} not known", name)))?;
+        Ok(json!({
+            "FromImage": image.names.first(),
+            "FromImageID": image.id,
+            "Container": container,
+            "OCIv1": {"os": image.os, "architecture": "amd64", "config": {}},
+        })
+        .to_string())
+    }
+
+    fn commit(
+        &self,
+        container: &str,
+        image: &str,
+        _config: &CommitConfig,
+    ) -> Result<(), KrunvmError> {
+        self.check("commit")?;
+        let mut files: Vec<String> = fs::read_dir(self.rootfs(container))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        self.state
+            .borrow_mut()
+            .commits
+            .push((image.to_string(), files));
+        Ok(())
+    }
+
+    fn images(&self) -> Result<Vec<ImageSummary>, KrunvmError> {
+        self.check("images")?;
+        Ok(self
+            .state
+            .borrow()
+            .images
+            .iter()
+            .map(FakeBackend::summary)
+            .collect())
+    }
+
+    fn pull(&self, image: &str) -> Result<String, KrunvmError> {
+        self.check("pull")?;
+        let id = format!("{:064x}", self.state.borrow().images.len() + 1);
+        self.add_image(&id, image);
+        Ok(id)
+    }
+
+    fn rmi(&self, image: &str, _force: bool) -> Result<(), KrunvmError> {
+        self.check("rmi")?;
+        let found = self.find(image);
+        if found.is_empty() {
+            return Err(KrunvmError::InvalidInput(format!(
+                "image {} not known",
+                image
+            )));
+        }
+        self.state
+            .borrow_mut()
+            .images
+            .retain(|candidate| !found.contains(&candidate.id));
+        Ok(())
+    }
+}
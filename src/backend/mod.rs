@@ -0,0 +1,157 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{KrunvmConfig, KrunvmError, VmConfig};
+
+mod buildah;
+mod oci;
+mod rootfs;
+
+#[cfg(test)]
+pub(crate) mod fake;
+
+pub use buildah::BuildahBackend;
+pub use oci::OciBackend;
+pub use rootfs::RootfsBackend;
+
+/// Name of the backend used when none has been configured.
+pub const DEFAULT_BACKEND: &str = "buildah";
+
+/// Name of the backend unpacking local OCI images without external tools.
+pub const OCI_BACKEND: &str = "oci";
+
+/// Name of the backend of VMs created from an existing root filesystem
+/// directory. It can't be selected as the default one.
+pub const ROOTFS_BACKEND: &str = "rootfs";
+
+/// Names accepted for `KrunvmConfig::backend`.
+pub const BACKENDS: [&str; 2] = [DEFAULT_BACKEND, OCI_BACKEND];
+
+/// Settings recorded in the configuration of a committed image. Unset
+/// values are inherited from the image the container was created from.
+#[derive(Debug, Default)]
+pub struct CommitConfig {
+    /// Environment variables, in "KEY=VALUE" format, to add.
+    pub env: Vec<String>,
+    pub workdir: Option<String>,
+    /// Command, along with its arguments, replacing both the entrypoint
+    /// and the command of the image.
+    pub command: Option<Vec<String>>,
+    /// Ports to expose, in "PORT/PROTOCOL" format.
+    pub ports: Vec<String>,
+}
+
+/// An image kept in the storage of a backend.
+#[derive(Clone, Debug, Default)]
+pub struct ImageSummary {
+    pub id: String,
+    /// References pointing to the image, which may be none at all.
+    pub names: Vec<String>,
+    /// Size of the image, in a human-readable format.
+    pub size: String,
+}
+
+/// Manages the containers providing the root filesystem of the VMs.
+pub trait ContainerBackend {
+    /// Creates a new container from `image`, returning its name. If `arch`
+    /// is given, the image for that architecture is used instead of the
+    /// host's one.
+    fn from(&self, image: &str, arch: Option<&str>) -> Result<String, KrunvmError>;
+
+    /// Mounts the container, returning the path to its root filesystem.
+    fn mount(&self, container: &str) -> Result<String, KrunvmError>;
+
+    fn umount(&self, container: &str) -> Result<(), KrunvmError>;
+
+    fn rm(&self, container: &str) -> Result<(), KrunvmError>;
+
+    /// Returns the configuration of an image or container, in the JSON
+    /// format of `buildah inspect`.
+    fn inspect(&self, name: &str) -> Result<String, KrunvmError>;
+
+    /// Saves the current contents of the container as `image`, with
+    /// `config` applied on top of its image configuration.
+    fn commit(
+        &self,
+        container: &str,
+        image: &str,
+        config: &CommitConfig,
+    ) -> Result<(), KrunvmError>;
+
+    /// Returns the images kept in the storage of the backend.
+    fn images(&self) -> Result<Vec<ImageSummary>, KrunvmError>;
+
+    /// Fetches `image` into the storage of the backend, returning its ID.
+    fn pull(&self, image: &str) -> Result<String, KrunvmError>;
+
+    /// Removes `image` from the storage of the backend. With `force`, the
+    /// containers using it are removed as well.
+    fn rmi(&self, image: &str, force: bool) -> Result<(), KrunvmError>;
+}
+
+/// Returns the backend called `name`, configured from `cfg`.
+pub fn get_backend(
+    cfg: &KrunvmConfig,
+    name: &str,
+) -> Result<Box<dyn ContainerBackend>, KrunvmError> {
+    match name {
+        // VMs created before backends were selectable don't record theirs.
+        "" | DEFAULT_BACKEND => Ok(Box::new(BuildahBackend::new(cfg))),
+        OCI_BACKEND => Ok(Box::new(OciBackend::new()?)),
+        ROOTFS_BACKEND => Ok(Box::new(RootfsBackend)),
+        _ => Err(KrunvmError::InvalidInput(format!(
+            "Unknown container backend {}, supported backends are: {}",
+            name,
+            BACKENDS.join(", ")
+        ))),
+    }
+}
+
+/// Checks `name` can be selected as the backend for new VMs.
+pub fn validate_backend(name: &str) -> Result<(), KrunvmError> {
+    if !BACKENDS.contains(&name) {
+        return Err(KrunvmError::InvalidInput(format!(
+            "Unknown container backend {}, supported backends are: {}",
+            name,
+            BACKENDS.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Provides the backends VMs and images are managed with, by name.
+pub trait Backends {
+    /// Returns the backend called `name`, configured from `cfg`.
+    fn get(&self, cfg: &KrunvmConfig, name: &str)
+        -> Result<Box<dyn ContainerBackend>, KrunvmError>;
+
+    /// Returns the backend to be used for new VMs.
+    fn default_backend(
+        &self,
+        cfg: &KrunvmConfig,
+    ) -> Result<Box<dyn ContainerBackend>, KrunvmError> {
+        self.get(cfg, &cfg.backend)
+    }
+
+    /// Returns the backend managing the container of an existing VM.
+    fn vm_backend(
+        &self,
+        cfg: &KrunvmConfig,
+        vmcfg: &VmConfig,
+    ) -> Result<Box<dyn ContainerBackend>, KrunvmError> {
+        self.get(cfg, &vmcfg.backend)
+    }
+}
+
+/// The backends shipped with krunvm, as returned by `get_backend`.
+pub struct SystemBackends;
+
+impl Backends for SystemBackends {
+    fn get(
+        &self,
+        cfg: &KrunvmConfig,
+        name: &str,
+    ) -> Result<Box<dyn ContainerBackend>, KrunvmError> {
+        get_backend(cfg, name)
+    }
+}
@@ -0,0 +1,1053 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256, Sha512};
+use tar::{Archive, EntryType};
+
+use super::{CommitConfig, ContainerBackend, ImageSummary};
+use crate::storage::{default_data_dir, is_valid_vm_name};
+use crate::KrunvmError;
+
+const CONTAINERS_DIR: &str = "containers";
+const ROOTFS_DIR: &str = "rootfs";
+const CONFIG_FILE: &str = "config.json";
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+/// Extended attribute holding the owner and mode of files unpacked without
+/// the privileges to set them, as used by containers/storage and honored by
+/// libkrun when exposing the root filesystem to the guest.
+const OVERRIDE_STAT_XATTR: &str = "user.containers.override_stat";
+/// Symlinks followed while resolving a path before giving up, as Linux.
+const MAX_SYMLINKS: usize = 40;
+
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+/// An entry of the `manifest.json` file written by `docker save`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+/// An image resolved from its source.
+struct Image {
+    /// Digest of the manifest, if the source records it.
+    digest: String,
+    /// Digest of the configuration, if the source records it.
+    id: String,
+    config: Value,
+    layers: Vec<Layer>,
+}
+
+struct Layer {
+    /// Path of the layer, relative to the root of the source.
+    path: PathBuf,
+    /// Digest of the layer, if the source records it.
+    digest: Option<String>,
+}
+
+/// A local directory holding an OCI image layout, or a tarball with either
+/// an OCI image layout (`oci-archive`) or the output of `docker save`
+/// (`docker-archive`).
+enum ImageSource {
+    Layout(PathBuf),
+    Archive(PathBuf),
+}
+
+impl ImageSource {
+    fn open(path: &Path) -> Result<ImageSource, Error> {
+        if fs::metadata(path)?.is_dir() {
+            Ok(ImageSource::Layout(path.to_path_buf()))
+        } else {
+            Ok(ImageSource::Archive(path.to_path_buf()))
+        }
+    }
+
+    /// Calls `f` with a reader for the file at `name`.
+    fn with_file<T>(
+        &self,
+        name: &Path,
+        f: impl FnOnce(&mut dyn Read) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        match self {
+            ImageSource::Layout(dir) => f(&mut File::open(dir.join(name))?),
+            ImageSource::Archive(path) => {
+                let mut name = normalize(name);
+                // Older versions of `docker save` link identical layers
+                // together, so follow a few levels of symlinks.
+                for _ in 0..8 {
+                    let mut archive = Archive::new(File::open(path)?);
+                    let mut target = None;
+                    for entry in archive.entries_with_seek()? {
+                        let mut entry = entry?;
+                        if normalize(&entry.path()?) != name {
+                            continue;
+                        }
+                        if entry.header().entry_type() != EntryType::Symlink {
+                            return f(&mut entry);
+                        }
+                        let link = entry.link_name()?.unwrap_or_default();
+                        target = Some(normalize(&name.parent().unwrap_or(&name).join(link)));
+                        break;
+                    }
+                    name = target.ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::NotFound,
+                            format!("{} not found in the archive", name.display()),
+                        )
+                    })?;
+                }
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("too many levels of symlinks for {}", name.display()),
+                ))
+            }
+        }
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, name: &Path) -> Result<T, Error> {
+        self.with_file(name, |reader| Ok(serde_json::from_reader(reader)?))
+    }
+}
+
+/// Returns `path` relative to the root of an archive, resolving "." and
+/// ".." without ever going above the root.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+fn blob_path(digest: &str) -> Result<PathBuf, Error> {
+    match digest.split_once(':') {
+        Some((algorithm, hash)) if !algorithm.contains('/') && !hash.contains('/') => {
+            Ok(Path::new("blobs").join(algorithm).join(hash))
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid digest {}", digest),
+        )),
+    }
+}
+
+fn hex_digest<D: Digest + Write>(reader: &mut dyn Read) -> Result<String, Error> {
+    let mut hasher = D::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Checks that the contents read from `reader` match `digest`.
+fn check_digest(reader: &mut dyn Read, digest: &str) -> Result<(), Error> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+    let (algorithm, expected) = digest
+        .split_once(':')
+        .ok_or_else(|| invalid(format!("invalid digest {}", digest)))?;
+    let actual = match algorithm {
+        "sha256" => hex_digest::<Sha256>(reader)?,
+        "sha512" => hex_digest::<Sha512>(reader)?,
+        _ => {
+            return Err(invalid(format!(
+                "unsupported digest algorithm {}",
+                algorithm
+            )))
+        }
+    };
+    if actual != expected {
+        return Err(invalid(format!(
+            "blob {} doesn't match its digest, got {}:{}",
+            digest, algorithm, actual
+        )));
+    }
+    Ok(())
+}
+
+/// Translates an architecture name as reported by Rust or passed by the
+/// user into the one used in OCI images.
+pub(super) fn oci_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    }
+}
+
+/// Picks the manifest for `arch` among `manifests`, falling back to the
+/// first one not stating a platform.
+fn select_manifest(manifests: Vec<Descriptor>, arch: &str) -> Result<Descriptor, Error> {
+    let mut fallback = None;
+    for manifest in manifests {
+        match manifest.platform {
+            Some(ref platform) if platform.os == "linux" && platform.architecture == arch => {
+                return Ok(manifest)
+            }
+            None if fallback.is_none() => fallback = Some(manifest),
+            _ => {}
+        }
+    }
+    fallback.ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("no image found for linux/{}", arch),
+        )
+    })
+}
+
+fn load_oci_image(
+    source: &ImageSource,
+    index: Index,
+    reference: Option<&str>,
+    arch: &str,
+) -> Result<Image, Error> {
+    let manifests: Vec<Descriptor> = match reference {
+        Some(reference) => index
+            .manifests
+            .into_iter()
+            .filter(|manifest| {
+                manifest
+                    .annotations
+                    .get(REF_NAME_ANNOTATION)
+                    .map(String::as_str)
+                    == Some(reference)
+            })
+            .collect(),
+        None => index.manifests,
+    };
+    if manifests.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no image named {}", reference.unwrap_or_default()),
+        ));
+    }
+
+    let mut descriptor = select_manifest(manifests, arch)?;
+    while INDEX_MEDIA_TYPES.contains(&descriptor.media_type.as_str()) {
+        let index: Index = source.read_json(&blob_path(&descriptor.digest)?)?;
+        descriptor = select_manifest(index.manifests, arch)?;
+    }
+
+    let manifest: Manifest = source.read_json(&blob_path(&descriptor.digest)?)?;
+    Ok(Image {
+        digest: descriptor.digest,
+        id: manifest.config.digest.clone(),
+        config: source.read_json(&blob_path(&manifest.config.digest)?)?,
+        layers: manifest
+            .layers
+            .iter()
+            .map(|layer| {
+                Ok(Layer {
+                    path: blob_path(&layer.digest)?,
+                    digest: Some(layer.digest.clone()),
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+    })
+}
+
+fn load_docker_image(
+    source: &ImageSource,
+    manifests: Vec<DockerManifest>,
+    reference: Option<&str>,
+) -> Result<Image, Error> {
+    let manifest = match reference {
+        Some(reference) => {
+            let tagged = format!("{}:latest", reference);
+            manifests.into_iter().find(|manifest| {
+                manifest
+                    .repo_tags
+                    .iter()
+                    .flatten()
+                    .any(|tag| tag == reference || *tag == tagged)
+            })
+        }
+        None if manifests.len() > 1 => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the archive contains several images, please select one with PATH:REFERENCE",
+            ))
+        }
+        None => manifests.into_iter().next(),
+    }
+    .ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("no image named {}", reference.unwrap_or_default()),
+        )
+    })?;
+
+    Ok(Image {
+        digest: String::new(),
+        id: String::new(),
+        config: source.read_json(Path::new(&manifest.config))?,
+        layers: manifest
+            .layers
+            .iter()
+            .map(|path| Layer {
+                path: PathBuf::from(path),
+                digest: None,
+            })
+            .collect(),
+    })
+}
+
+fn load_image(source: &ImageSource, reference: Option<&str>, arch: &str) -> Result<Image, Error> {
+    match source.read_json(Path::new("index.json")) {
+        Ok(index) => return load_oci_image(source, index, reference, arch),
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        Err(_) => {}
+    }
+    match source.read_json(Path::new("manifest.json")) {
+        Ok(manifests) => load_docker_image(source, manifests, reference),
+        Err(err) if err.kind() == ErrorKind::NotFound => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "not an OCI image layout nor a docker archive",
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+/// Splits an image reference in the path to its source and, optionally,
+/// the name of the image within it, accepting the `oci:`, `oci-archive:`
+/// and `docker-archive:` prefixes used by buildah.
+fn parse_image_ref(image: &str) -> (&str, Option<&str>) {
+    let image = ["oci:", "oci-archive:", "docker-archive:"]
+        .iter()
+        .find_map(|prefix| image.strip_prefix(prefix))
+        .unwrap_or(image);
+
+    if !Path::new(image).exists() {
+        for (idx, _) in image.match_indices(':') {
+            if Path::new(&image[..idx]).exists() {
+                return (&image[..idx], Some(&image[idx + 1..]));
+            }
+        }
+    }
+    (image, None)
+}
+
+/// Returns the description of an image or container, mimicking the fields
+/// of `buildah inspect` that krunvm and libkrun rely on.
+fn inspect_json(reference: &str, image: &Image, container: &str) -> Result<String, Error> {
+    let inspect = json!({
+        "Type": "oci",
+        "FromImage": reference,
+        "FromImageID": image.id,
+        "FromImageDigest": image.digest,
+        "Container": container,
+        "OCIv1": image.config,
+    });
+    Ok(serde_json::to_string_pretty(&inspect)?)
+}
+
+/// Makes `path`, and every directory below it, writable by the owner so
+/// it can be modified or removed.
+fn make_writable(path: &Path) -> Result<(), Error> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+    let mode = metadata.permissions().mode();
+    if mode & 0o700 != 0o700 {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o700))?;
+    }
+    for entry in fs::read_dir(path)? {
+        make_writable(&entry?.path())?;
+    }
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            make_writable(path)?;
+            fs::remove_dir_all(path)
+        }
+        Ok(_) => fs::remove_file(path),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Opens a layer, transparently decompressing it if needed.
+fn layer_archive<'a>(reader: &'a mut dyn Read) -> Result<Archive<Box<dyn Read + 'a>>, Error> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+    let reader: Box<dyn Read + 'a> = if magic.starts_with(&GZIP_MAGIC) {
+        Box::new(GzDecoder::new(reader))
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "zstd compressed layers are not supported",
+        ));
+    } else {
+        Box::new(reader)
+    };
+    Ok(Archive::new(reader))
+}
+
+/// Records the owner and mode of the file at `path` in its
+/// `OVERRIDE_STAT_XATTR` extended attribute.
+fn record_owner(path: &Path, uid: u64, gid: u64, mode: u32) -> Result<(), Error> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(OVERRIDE_STAT_XATTR)?;
+    let value = format!("{}:{}:0{:o}", uid, gid, mode & 0o7777);
+    let ret = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        let err = Error::last_os_error();
+        return Err(Error::new(
+            err.kind(),
+            format!("recording the owner of {}: {}", path.display(), err),
+        ));
+    }
+    Ok(())
+}
+
+/// Applies the layers of an image, in order, on top of a root filesystem.
+struct Unpacker {
+    rootfs: PathBuf,
+    /// Whether files can be given their owner from the image, as root or in
+    /// a user namespace. Otherwise, it's recorded in an extended attribute.
+    preserve_owners: bool,
+    /// Modes of the directories unpacked so far, by their path on the host.
+    /// They are kept writable while unpacking, and only get their final
+    /// mode once every layer has been applied.
+    dir_modes: HashMap<PathBuf, u32>,
+}
+
+impl Unpacker {
+    fn new(rootfs: &Path) -> Result<Unpacker, Error> {
+        Ok(Unpacker {
+            rootfs: rootfs.canonicalize()?,
+            preserve_owners: unsafe { libc::geteuid() } == 0,
+            dir_modes: HashMap::new(),
+        })
+    }
+
+    /// Resolves `path` the way the guest would, following symlinks with
+    /// the root filesystem as their root, so neither absolute links nor
+    /// ".." ever lead outside of it. Returns None if part of `path` is
+    /// missing, unless `create` is set, in which case missing directories
+    /// are created.
+    fn resolve(&self, path: &Path, create: bool) -> Result<Option<PathBuf>, Error> {
+        let mut resolved = self.rootfs.clone();
+        // Components left to resolve, the next one last, with ".." kept
+        // as such.
+        let mut pending = Vec::new();
+        let push_components = |pending: &mut Vec<OsString>, path: &Path| {
+            for component in path.components().rev() {
+                match component {
+                    Component::Normal(part) => pending.push(part.to_os_string()),
+                    Component::ParentDir => pending.push(OsString::from("..")),
+                    _ => {}
+                }
+            }
+        };
+        push_components(&mut pending, path);
+
+        let mut links = 0;
+        while let Some(part) = pending.pop() {
+            if part == ".." {
+                if resolved != self.rootfs {
+                    resolved.pop();
+                }
+                continue;
+            }
+            let next = resolved.join(&part);
+            match fs::symlink_metadata(&next) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("too many levels of symlinks for {}", path.display()),
+                        ));
+                    }
+                    let target = fs::read_link(&next)?;
+                    if target.is_absolute() {
+                        resolved = self.rootfs.clone();
+                    }
+                    push_components(&mut pending, &target);
+                }
+                Ok(_) => resolved = next,
+                Err(err) if err.kind() == ErrorKind::NotFound && create => {
+                    fs::create_dir(&next)?;
+                    resolved = next;
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Some(resolved))
+    }
+
+    /// Returns where `path` lives on the host, resolving every component
+    /// but the last one, or None if its parent directory is missing.
+    fn host_path(&self, path: &Path) -> Result<Option<PathBuf>, Error> {
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return Ok(Some(self.rootfs.clone())),
+        };
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        Ok(self.resolve(parent, false)?.map(|parent| parent.join(name)))
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<(), Error> {
+        if let Some(host_path) = self.host_path(path)? {
+            remove_path(&host_path)?;
+            self.dir_modes.retain(|dir, _| !dir.starts_with(&host_path));
+        }
+        Ok(())
+    }
+
+    fn unpack_layer(&mut self, source: &ImageSource, layer: &Layer) -> Result<(), Error> {
+        // Nothing is unpacked from a layer that isn't the expected one.
+        if let Some(digest) = layer.digest.as_deref() {
+            source.with_file(&layer.path, |reader| check_digest(reader, digest))?;
+        }
+        // Whiteouts only hide files from the lower layers, so apply them
+        // all before unpacking the contents of this one.
+        source.with_file(&layer.path, |reader| self.apply_whiteouts(reader))?;
+        source.with_file(&layer.path, |reader| self.extract(reader))
+    }
+
+    fn apply_whiteouts(&mut self, reader: &mut dyn Read) -> Result<(), Error> {
+        let mut archive = layer_archive(reader)?;
+        for entry in archive.entries()? {
+            let path = normalize(&entry?.path()?);
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+            if name == OPAQUE_WHITEOUT {
+                let host_dir = match self.resolve(dir, false)? {
+                    Some(host_dir) if host_dir.is_dir() => host_dir,
+                    _ => continue,
+                };
+                for child in fs::read_dir(host_dir)? {
+                    self.remove(&dir.join(child?.file_name()))?;
+                }
+            } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                self.remove(&dir.join(hidden))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn extract(&mut self, reader: &mut dyn Read) -> Result<(), Error> {
+        let mut archive = layer_archive(reader)?;
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.set_preserve_ownerships(self.preserve_owners);
+        archive.set_unpack_xattrs(false);
+        archive.set_overwrite(true);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = normalize(&entry.path()?);
+            let is_whiteout = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_none_or(|name| name.starts_with(WHITEOUT_PREFIX));
+            if is_whiteout {
+                continue;
+            }
+
+            let entry_type = entry.header().entry_type();
+            // Device nodes can't be created without privileges, and the
+            // guest gets its own ones from devtmpfs anyway.
+            if entry_type.is_block_special() || entry_type.is_character_special() {
+                continue;
+            }
+
+            let parent = path.parent().unwrap_or_else(|| Path::new(""));
+            let host_path = match (self.resolve(parent, true)?, path.file_name()) {
+                (Some(parent), Some(name)) => parent.join(name),
+                _ => continue,
+            };
+
+            // A directory replacing a file, or the other way around, needs
+            // the old entry out of the way.
+            if let Ok(metadata) = fs::symlink_metadata(&host_path) {
+                if metadata.is_dir() != entry_type.is_dir() {
+                    self.remove(&path)?;
+                }
+            }
+
+            if entry_type.is_hard_link() {
+                // Hard links point to another entry of the image, which
+                // has to be looked up in the root filesystem as well.
+                let target = match entry.link_name()? {
+                    Some(target) => self.host_path(&normalize(&target))?,
+                    None => None,
+                };
+                if let Some(target) = target {
+                    remove_path(&host_path)?;
+                    fs::hard_link(target, &host_path)?;
+                }
+                continue;
+            }
+
+            let mode = entry.header().mode()?;
+            entry.unpack(&host_path)?;
+            if entry_type.is_dir() {
+                fs::set_permissions(&host_path, fs::Permissions::from_mode(mode | 0o700))?;
+                // When the mode is recorded, the guest sees that one, and
+                // the directory is better left accessible to its owner.
+                let final_mode = if self.preserve_owners {
+                    mode
+                } else {
+                    mode | 0o700
+                };
+                self.dir_modes.insert(host_path.clone(), final_mode);
+            } else if !self.preserve_owners && !entry_type.is_symlink() && mode & 0o600 != 0o600 {
+                fs::set_permissions(&host_path, fs::Permissions::from_mode(mode | 0o600))?;
+            }
+            // Extended attributes in the user namespace can't be set on
+            // symlinks, whose owner matters little anyway.
+            if !self.preserve_owners && !entry_type.is_symlink() {
+                let header = entry.header();
+                record_owner(&host_path, header.uid()?, header.gid()?, mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the modes of the unpacked directories, deepest first.
+    fn finish(self) -> Result<(), Error> {
+        let mut dirs: Vec<(PathBuf, u32)> = self.dir_modes.into_iter().collect();
+        dirs.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        for (path, mode) in dirs {
+            match fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777)) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Backend unpacking local OCI images into root filesystem directories
+/// managed by krunvm, with no need for external tools.
+pub struct OciBackend {
+    containers_dir: PathBuf,
+}
+
+impl OciBackend {
+    pub fn new() -> Result<OciBackend, KrunvmError> {
+        let data_dir = default_data_dir()
+            .map_err(|err| KrunvmError::Io("determining the data directory".to_string(), err))?;
+        Ok(OciBackend {
+            containers_dir: data_dir.join(CONTAINERS_DIR),
+        })
+    }
+
+    fn container_dir(&self, container: &str) -> Result<PathBuf, KrunvmError> {
+        if !is_valid_vm_name(container) {
+            return Err(KrunvmError::InvalidInput(format!(
+                "Invalid container name {}",
+                container
+            )));
+        }
+        Ok(self.containers_dir.join(container))
+    }
+
+    fn load_image(
+        &self,
+        image: &str,
+        arch: Option<&str>,
+    ) -> Result<(ImageSource, Image), KrunvmError> {
+        let (path, reference) = parse_image_ref(image);
+        let arch = oci_arch(arch.unwrap_or(std::env::consts::ARCH));
+
+        ImageSource::open(Path::new(path))
+            .and_then(|source| {
+                let image = load_image(&source, reference, arch)?;
+                Ok((source, image))
+            })
+            .map_err(|err| KrunvmError::Io(format!("reading image {}", image), err))
+    }
+
+    /// Creates a new, empty, container directory with a name derived from
+    /// the image it will hold.
+    fn new_container_dir(&self, image: &str) -> Result<(String, PathBuf), Error> {
+        fs::create_dir_all(&self.containers_dir)?;
+
+        let (path, _) = parse_image_ref(image);
+        let base = Path::new(path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .filter(|stem| is_valid_vm_name(stem))
+            .unwrap_or("image");
+
+        for idx in 0.. {
+            let name = match idx {
+                0 => format!("{}-working-container", base),
+                idx => format!("{}-working-container-{}", base, idx),
+            };
+            let dir = self.containers_dir.join(&name);
+            match fs::create_dir(&dir) {
+                Ok(()) => return Ok((name, dir)),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl ContainerBackend for OciBackend {
+    fn from(&self, image: &str, arch: Option<&str>) -> Result<String, KrunvmError> {
+        let (source, resolved) = self.load_image(image, arch)?;
+
+        let (name, dir) = self
+            .new_container_dir(image)
+            .map_err(|err| KrunvmError::Io("creating the container directory".to_string(), err))?;
+
+        let unpack = || -> Result<(), Error> {
+            let rootfs = dir.join(ROOTFS_DIR);
+            fs::create_dir(&rootfs)?;
+
+            let mut unpacker = Unpacker::new(&rootfs)?;
+            for layer in resolved.layers.iter() {
+                unpacker.unpack_layer(&source, layer)?;
+            }
+            unpacker.finish()?;
+
+            fs::write(
+                dir.join(CONFIG_FILE),
+                inspect_json(image, &resolved, &name)?,
+            )
+        };
+
+        if let Err(err) = unpack() {
+            let _ = remove_path(&dir);
+            return Err(KrunvmError::Io(format!("unpacking image {}", image), err));
+        }
+
+        Ok(name)
+    }
+
+    fn mount(&self, container: &str) -> Result<String, KrunvmError> {
+        let rootfs = self.container_dir(container)?.join(ROOTFS_DIR);
+        if !rootfs.is_dir() {
+            return Err(KrunvmError::ContainerNotFound(container.to_string()));
+        }
+        Ok(rootfs.to_string_lossy().into_owned())
+    }
+
+    fn umount(&self, _container: &str) -> Result<(), KrunvmError> {
+        // The root filesystem is a plain directory, always available.
+        Ok(())
+    }
+
+    fn rm(&self, container: &str) -> Result<(), KrunvmError> {
+        let dir = self.container_dir(container)?;
+        if fs::symlink_metadata(&dir).is_err() {
+            return Err(KrunvmError::ContainerNotFound(container.to_string()));
+        }
+        remove_path(&dir)
+            .map_err(|err| KrunvmError::Io(format!("removing container {}", container), err))
+    }
+
+    fn inspect(&self, name: &str) -> Result<String, KrunvmError> {
+        if is_valid_vm_name(name) {
+            let path = self.containers_dir.join(name).join(CONFIG_FILE);
+            if path.is_file() {
+                return fs::read_to_string(&path)
+                    .map_err(|err| KrunvmError::Io(format!("reading {}", path.display()), err));
+            }
+        }
+
+        let (_, image) = self.load_image(name, None)?;
+        inspect_json(name, &image, "")
+            .map_err(|err| KrunvmError::Io(format!("inspecting image {}", name), err))
+    }
+
+    fn commit(
+        &self,
+        _container: &str,
+        _image: &str,
+        _config: &CommitConfig,
+    ) -> Result<(), KrunvmError> {
+        Err(KrunvmError::InvalidInput(
+            "The oci backend doesn't support committing containers".to_string(),
+        ))
+    }
+
+    fn images(&self) -> Result<Vec<ImageSummary>, KrunvmError> {
+        // Images are read directly from their source, none are stored.
+        Ok(Vec::new())
+    }
+
+    fn pull(&self, _image: &str) -> Result<String, KrunvmError> {
+        Err(KrunvmError::InvalidInput(
+            "The oci backend only supports local images, which don't need to be pulled".to_string(),
+        ))
+    }
+
+    fn rmi(&self, image: &str, _force: bool) -> Result<(), KrunvmError> {
+        Err(KrunvmError::InvalidInput(format!(
+            "The oci backend doesn't store images, {} can be removed directly",
+            image
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::backend::fake::TestDir;
+
+    /// Returns a layer made of the regular files in `files`, with the
+    /// given contents.
+    fn layer(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in files.iter() {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            builder
+                .append_data(&mut header, path, data.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn apply(unpacker: &mut Unpacker, layer: Vec<u8>) {
+        unpacker
+            .apply_whiteouts(&mut Cursor::new(layer.clone()))
+            .unwrap();
+        unpacker.extract(&mut Cursor::new(layer)).unwrap();
+    }
+
+    #[test]
+    fn checks_digests() {
+        let digest = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        check_digest(&mut Cursor::new("hello"), digest).unwrap();
+        let err = check_digest(&mut Cursor::new("hellO"), digest).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(check_digest(&mut Cursor::new("hello"), "md5:5d41402abc4b2a76").is_err());
+    }
+
+    #[test]
+    fn normalizes_paths_below_the_root() {
+        assert_eq!(normalize(Path::new("/a/./b/../c")), Path::new("a/c"));
+        assert_eq!(normalize(Path::new("../../a")), Path::new("a"));
+    }
+
+    #[test]
+    fn resolves_host_paths_inside_the_rootfs() {
+        let dir = TestDir::new();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc")).unwrap();
+        fs::create_dir_all(rootfs.join("run")).unwrap();
+        fs::create_dir_all(rootfs.join("var")).unwrap();
+        symlink(dir.path(), rootfs.join("escape")).unwrap();
+        symlink("../..", rootfs.join("etc/up")).unwrap();
+        symlink("/run", rootfs.join("var/run")).unwrap();
+        symlink("loop", rootfs.join("loop")).unwrap();
+        let unpacker = Unpacker::new(&rootfs).unwrap();
+        let rootfs = rootfs.canonicalize().unwrap();
+
+        assert_eq!(
+            unpacker.host_path(Path::new("etc/passwd")).unwrap(),
+            Some(rootfs.join("etc/passwd"))
+        );
+        assert_eq!(unpacker.host_path(Path::new("missing/file")).unwrap(), None);
+        // Symlinks are followed with the root filesystem as their root.
+        assert_eq!(
+            unpacker.host_path(Path::new("var/run/file")).unwrap(),
+            Some(rootfs.join("run/file"))
+        );
+        assert_eq!(
+            unpacker.host_path(Path::new("etc/up/file")).unwrap(),
+            Some(rootfs.join("file"))
+        );
+        assert_eq!(unpacker.host_path(Path::new("escape/file")).unwrap(), None);
+        assert!(unpacker.host_path(Path::new("loop/file")).is_err());
+        // Only the parent directory is resolved.
+        assert_eq!(
+            unpacker.host_path(Path::new("var/run")).unwrap(),
+            Some(rootfs.join("var/run"))
+        );
+    }
+
+    #[test]
+    fn records_owners_when_unprivileged() {
+        let dir = TestDir::new();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        let mut unpacker = Unpacker::new(&rootfs).unwrap();
+        unpacker.preserve_owners = false;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(0);
+        header.set_mode(0o4750);
+        header.set_uid(1000);
+        header.set_gid(100);
+        builder.append_data(&mut header, "file", &[][..]).unwrap();
+        apply(&mut unpacker, builder.into_inner().unwrap());
+
+        let path = CString::new(rootfs.join("file").as_os_str().as_bytes()).unwrap();
+        let name = CString::new(OVERRIDE_STAT_XATTR).unwrap();
+        let mut value = [0u8; 64];
+        let len = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        assert!(len > 0);
+        assert_eq!(&value[..len as usize], b"1000:100:04750");
+    }
+
+    #[test]
+    fn unpacks_through_absolute_symlinks() {
+        let dir = TestDir::new();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("run")).unwrap();
+        fs::create_dir_all(rootfs.join("var")).unwrap();
+        symlink("/run", rootfs.join("var/run")).unwrap();
+        fs::write(rootfs.join("run/old"), "").unwrap();
+        let mut unpacker = Unpacker::new(&rootfs).unwrap();
+
+        apply(
+            &mut unpacker,
+            layer(&[("var/run/.wh.old", ""), ("var/run/new", "new")]),
+        );
+        unpacker.finish().unwrap();
+
+        assert!(!rootfs.join("run/old").exists());
+        assert_eq!(fs::read_to_string(rootfs.join("run/new")).unwrap(), "new");
+    }
+
+    #[test]
+    fn applies_whiteouts() {
+        let dir = TestDir::new();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        let mut unpacker = Unpacker::new(&rootfs).unwrap();
+        apply(
+            &mut unpacker,
+            layer(&[("a", "a"), ("b", "b"), ("dir/c", "c"), ("dir/d", "d")]),
+        );
+        apply(
+            &mut unpacker,
+            layer(&[(".wh.a", ""), ("dir/.wh..wh..opq", ""), ("dir/e", "e")]),
+        );
+        unpacker.finish().unwrap();
+
+        assert!(!rootfs.join("a").exists());
+        assert!(!rootfs.join(".wh.a").exists());
+        assert_eq!(fs::read_to_string(rootfs.join("b")).unwrap(), "b");
+        let mut children: Vec<_> = fs::read_dir(rootfs.join("dir"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        children.sort();
+        assert_eq!(children, vec!["e"]);
+    }
+
+    #[test]
+    fn ignores_whiteouts_escaping_the_rootfs() {
+        let dir = TestDir::new();
+        let rootfs = dir.path().join("rootfs");
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("victim"), "").unwrap();
+        symlink(&outside, rootfs.join("link")).unwrap();
+        let mut unpacker = Unpacker::new(&rootfs).unwrap();
+
+        unpacker
+            .apply_whiteouts(&mut Cursor::new(layer(&[
+                ("link/.wh.victim", ""),
+                ("link/.wh..wh..opq", ""),
+            ])))
+            .unwrap();
+        assert!(outside.join("victim").exists());
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde_json::json;
+
+use super::oci::oci_arch;
+use super::{CommitConfig, ContainerBackend, ImageSummary};
+use crate::KrunvmError;
+
+const KRUN_CONFIG_FILE: &str = ".krun_config.json";
+
+/// Backend for VMs whose root filesystem is an existing directory, owned
+/// by the user. The "container" is the path to the directory itself.
+pub struct RootfsBackend;
+
+impl ContainerBackend for RootfsBackend {
+    fn from(&self, path: &str, _arch: Option<&str>) -> Result<String, KrunvmError> {
+        let rootfs = Path::new(path)
+            .canonicalize()
+            .map_err(|err| KrunvmError::Io(format!("accessing {}", path), err))?;
+        if !rootfs.is_dir() {
+            return Err(KrunvmError::InvalidInput(format!(
+                "{} is not a directory",
+                path
+            )));
+        }
+        Ok(rootfs.to_string_lossy().into_owned())
+    }
+
+    fn mount(&self, container: &str) -> Result<String, KrunvmError> {
+        if !Path::new(container).is_dir() {
+            return Err(KrunvmError::ContainerNotFound(container.to_string()));
+        }
+        Ok(container.to_string())
+    }
+
+    fn umount(&self, _container: &str) -> Result<(), KrunvmError> {
+        Ok(())
+    }
+
+    fn rm(&self, _container: &str) -> Result<(), KrunvmError> {
+        // The directory belongs to the user, it's only removed on request.
+        Ok(())
+    }
+
+    /// Returns the `.krun_config.json` already present in the directory, or
+    /// an empty image configuration if there is none.
+    fn inspect(&self, container: &str) -> Result<String, KrunvmError> {
+        let path = Path::new(container).join(KRUN_CONFIG_FILE);
+        match fs::read_to_string(&path) {
+            Ok(config) => Ok(config),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let inspect = json!({
+                    "Type": "rootfs",
+                    "FromImage": "",
+                    "Container": container,
+                    "OCIv1": {
+                        "architecture": oci_arch(std::env::consts::ARCH),
+                        "os": "linux",
+                        "config": {
+                            "Cmd": ["/bin/sh"],
+                        },
+                    },
+                });
+                Ok(serde_json::to_string_pretty(&inspect).unwrap())
+            }
+            Err(err) => Err(KrunvmError::Io(format!("reading {}", path.display()), err)),
+        }
+    }
+
+    fn commit(
+        &self,
+        _container: &str,
+        _image: &str,
+        _config: &CommitConfig,
+    ) -> Result<(), KrunvmError> {
+        Err(KrunvmError::InvalidInput(
+            "VMs created from a root filesystem directory can't be committed".to_string(),
+        ))
+    }
+
+    fn images(&self) -> Result<Vec<ImageSummary>, KrunvmError> {
+        Ok(Vec::new())
+    }
+
+    fn pull(&self, _image: &str) -> Result<String, KrunvmError> {
+        Err(KrunvmError::InvalidInput(
+            "VMs created from a root filesystem directory don't use images".to_string(),
+        ))
+    }
+
+    fn rmi(&self, _image: &str, _force: bool) -> Result<(), KrunvmError> {
+        Err(KrunvmError::InvalidInput(
+            "VMs created from a root filesystem directory don't use images".to_string(),
+        ))
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Agent running inside the guest, which runs the command of the VM and
+//! serves `krunvm exec` requests over vsock.
+//!
+//! It's installed in the root filesystem of the VM by `krunvm start`, so
+//! it must be built for Linux, preferably as a static binary, and can't
+//! depend on the krunvm library, which links to libkrun.
+
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+#[path = "../agent.rs"]
+mod agent;
+
+#[cfg(target_os = "linux")]
+mod guest {
+    use std::env;
+    use std::fs::File;
+    use std::io::{self, Error, Read, Write};
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{self, Command, ExitStatus, Stdio};
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::agent::{read_frame, write_frame, ExecRequest, AGENT_PORT, EXIT, REQUEST};
+    use super::agent::{SHUTDOWN, STDERR, STDIN, STDOUT};
+
+    /// Pid of the command of the VM, once it's running.
+    static MAIN_PID: AtomicI32 = AtomicI32::new(0);
+
+    fn exit_code(status: ExitStatus) -> i32 {
+        status
+            .code()
+            .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+    }
+
+    fn listen() -> io::Result<i32> {
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_port = AGENT_PORT;
+        addr.svm_cid = libc::VMADDR_CID_ANY;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 || unsafe { libc::listen(fd, 16) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(fd)
+    }
+
+    /// Copies everything read from `reader` to the connection, as frames of
+    /// `kind`.
+    fn pump(mut reader: impl Read, conn: Arc<Mutex<File>>, kind: u8) {
+        let mut buf = [0u8; 8192];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => {
+                    if write_frame(&mut *conn.lock().unwrap(), kind, &buf[..len]).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn run_request(mut conn: File) -> io::Result<()> {
+        let request: ExecRequest = match read_frame(&mut conn)? {
+            Some((REQUEST, payload)) => serde_json::from_slice(&payload)?,
+            Some((SHUTDOWN, _)) => {
+                let pid = MAIN_PID.load(Ordering::SeqCst);
+                if pid > 0 {
+                    unsafe { libc::kill(pid, libc::SIGTERM) };
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        let mut reader = conn.try_clone()?;
+        let conn = Arc::new(Mutex::new(conn));
+
+        let mut cmd = Command::new(request.command.first().map_or("/bin/sh", |c| c.as_str()));
+        cmd.args(request.command.iter().skip(1))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        for pair in request.env.iter() {
+            if let Some((key, value)) = pair.split_once('=') {
+                cmd.env(key, value);
+            }
+        }
+        if !request.workdir.is_empty() {
+            cmd.current_dir(&request.workdir);
+        }
+
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                let mut conn = conn.lock().unwrap();
+                let message = format!("krunvm-agent: {:?}: {}\n", request.command, err);
+                write_frame(&mut *conn, STDERR, message.as_bytes())?;
+                return write_frame(&mut *conn, EXIT, &127i32.to_be_bytes());
+            }
+        };
+
+        let mut stdin = child.stdin.take();
+        thread::spawn(move || {
+            while let Ok(Some((STDIN, data))) = read_frame(&mut reader) {
+                let written = match stdin.as_mut() {
+                    Some(stdin) if !data.is_empty() => stdin.write_all(&data).is_ok(),
+                    _ => false,
+                };
+                if !written {
+                    break;
+                }
+            }
+            // Closes the standard input of the command.
+            drop(stdin);
+        });
+
+        let stdout = child.stdout.take().map(|stdout| {
+            let conn = conn.clone();
+            thread::spawn(move || pump(stdout, conn, STDOUT))
+        });
+        let stderr = child.stderr.take().map(|stderr| {
+            let conn = conn.clone();
+            thread::spawn(move || pump(stderr, conn, STDERR))
+        });
+
+        let status = child.wait()?;
+        for pump in vec![stdout, stderr].into_iter().flatten() {
+            let _ = pump.join();
+        }
+
+        let code = exit_code(status);
+        let mut conn = conn.lock().unwrap();
+        write_frame(&mut *conn, EXIT, &code.to_be_bytes())
+    }
+
+    fn serve(fd: i32) {
+        loop {
+            let conn = unsafe {
+                libc::accept4(
+                    fd,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                    libc::SOCK_CLOEXEC,
+                )
+            };
+            if conn < 0 {
+                continue;
+            }
+            let conn = unsafe { File::from_raw_fd(conn) };
+            thread::spawn(move || {
+                if let Err(err) = run_request(conn) {
+                    eprintln!("krunvm-agent: {}", err);
+                }
+            });
+        }
+    }
+
+    /// Serves exec requests in the background while running the command
+    /// given in the arguments, exiting with its exit code.
+    pub fn main() {
+        let args: Vec<String> = env::args().skip(1).collect();
+        if args.is_empty() {
+            eprintln!("Usage: krunvm-agent COMMAND [ARGS...]");
+            process::exit(2);
+        }
+
+        match listen() {
+            Ok(fd) => {
+                thread::spawn(move || serve(fd));
+            }
+            Err(err) => eprintln!("krunvm-agent: couldn't listen on vsock: {}", err),
+        }
+
+        let mut child = match Command::new(&args[0]).args(&args[1..]).spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                eprintln!("krunvm-agent: {}: {}", args[0], err);
+                process::exit(127);
+            }
+        };
+        MAIN_PID.store(child.id() as i32, Ordering::SeqCst);
+        match child.wait() {
+            Ok(status) => process::exit(exit_code(status)),
+            Err(err) => {
+                eprintln!("krunvm-agent: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() {
+    guest::main();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("krunvm-agent only runs inside Linux guests");
+    std::process::exit(1);
+}
@@ -13,6 +13,12 @@ extern "C" {
     pub fn krun_set_port_map(ctx: u32, port_map: *const *const c_char) -> i32;
     pub fn krun_set_workdir(ctx: u32, workdir_path: *const c_char) -> i32;
     pub fn krun_add_virtiofs(ctx: u32, tag: *const c_char, path: *const c_char) -> i32;
+    pub fn krun_add_vsock_port2(
+        ctx: u32,
+        port: u32,
+        c_filepath: *const c_char,
+        listen: bool,
+    ) -> i32;
     pub fn krun_set_exec(
         ctx: u32,
         exec_path: *const c_char,
@@ -20,5 +26,7 @@ extern "C" {
         envp: *const *const c_char,
     ) -> i32;
     pub fn krun_set_env(ctx: u32, envp: *const *const c_char) -> i32;
+    pub fn krun_set_rlimits(ctx: u32, rlimits: *const *const c_char) -> i32;
+    pub fn krun_set_console_output(ctx: u32, c_filepath: *const c_char) -> i32;
     pub fn krun_start_enter(ctx: u32) -> i32;
 }
@@ -0,0 +1,27 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::PathBuf;
+
+use clap::Args;
+use krunvm::backend::SystemBackends;
+use krunvm::manifest::{apply_manifest, Manifest};
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// Create or update microVMs from a manifest
+#[derive(Args, Debug)]
+pub struct ApplyCmd {
+    /// Manifest describing the microVMs
+    #[arg(short, long = "file")]
+    file: PathBuf,
+}
+
+impl ApplyCmd {
+    pub fn run(self, cfg: &mut KrunvmConfig) -> Result<(), KrunvmError> {
+        let manifest = Manifest::from_file(&self.file)?;
+
+        apply_manifest(cfg, &SystemBackends, manifest, &mut |report| {
+            println!("{}", report)
+        })
+    }
+}
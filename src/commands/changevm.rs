@@ -2,10 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use clap::Args;
-use std::collections::HashMap;
-
-use crate::utils::{path_pairs_to_hash_map, port_pairs_to_hash_map, PathPair, PortPair};
-use crate::{KrunvmConfig, APP_NAME};
+use krunvm::utils::{
+    env_pairs_to_hash_map, path_pairs_to_hash_map, port_pairs_to_hash_map, ulimits_to_hash_map,
+    EnvPair, PathPair, PortPair, Ulimit,
+};
+use krunvm::vm::{change_vm, ChangeOptions};
+use krunvm::{KrunvmConfig, KrunvmError};
 
 use super::list::printvm;
 
@@ -36,7 +38,7 @@ pub struct ChangeVmCmd {
     remove_volumes: bool,
 
     /// Volume(s) in form "host_path:guest_path" to be exposed to the guest
-    #[arg(short, long = "volume")]
+    #[arg(long = "volume")]
     volumes: Vec<PathPair>,
 
     /// Remove all port mappings
@@ -46,96 +48,69 @@ pub struct ChangeVmCmd {
     /// Port(s) in format "host_port:guest_port" to be exposed to the host
     #[arg(long = "port")]
     ports: Vec<PortPair>,
+
+    /// Environment variable(s) in format "key=value" to be set in the guest
+    #[arg(long = "env")]
+    envs: Vec<EnvPair>,
+
+    /// Environment variable(s) to be removed
+    #[arg(long = "unset-env")]
+    unset_envs: Vec<String>,
+
+    /// Resource limit(s) of the guest in format "resource=soft[:hard]" to be added or replaced
+    #[arg(long = "ulimit")]
+    ulimits: Vec<Ulimit>,
+
+    /// Resource limit(s) to be removed
+    #[arg(long = "unset-ulimit")]
+    unset_ulimits: Vec<String>,
+
+    /// Command to run when the microVM is started without one
+    #[arg(long, conflicts_with = "remove_command")]
+    command: Option<String>,
+
+    /// Arguments to be passed to the command
+    #[arg(last = true, requires = "command")]
+    args: Vec<String>,
+
+    /// Remove the command, falling back to the image's default
+    #[arg(long)]
+    remove_command: bool,
+
+    /// Log level of libkrun, from 0 (off) to 5 (trace)
+    #[arg(long)]
+    krun_log_level: Option<u32>,
 }
 
 impl ChangeVmCmd {
-    pub fn run(self, cfg: &mut KrunvmConfig) {
-        let mut cfg_changed = false;
-
-        let vmcfg = if let Some(new_name) = &self.new_name {
-            if cfg.vmconfig_map.contains_key(new_name) {
-                println!("A VM with name {} already exists", new_name);
-                std::process::exit(-1);
-            }
-
-            let mut vmcfg = match cfg.vmconfig_map.remove(&self.name) {
-                None => {
-                    println!("No VM found with name {}", &self.name);
-                    std::process::exit(-1);
-                }
-                Some(vmcfg) => vmcfg,
-            };
-
-            cfg_changed = true;
-            let name = new_name.to_string();
-            vmcfg.name = name.clone();
-            cfg.vmconfig_map.insert(name.clone(), vmcfg);
-            cfg.vmconfig_map.get_mut(&name).unwrap()
-        } else {
-            match cfg.vmconfig_map.get_mut(&self.name) {
-                None => {
-                    println!("No VM found with name {}", self.name);
-                    std::process::exit(-1);
-                }
-                Some(vmcfg) => vmcfg,
-            }
+    pub fn run(self, cfg: &mut KrunvmConfig) -> Result<(), KrunvmError> {
+        let opts = ChangeOptions {
+            new_name: self.new_name,
+            cpus: self.cpus,
+            mem: self.mem,
+            workdir: self.workdir,
+            remove_volumes: self.remove_volumes,
+            mapped_volumes: path_pairs_to_hash_map(self.volumes),
+            remove_ports: self.remove_ports,
+            mapped_ports: port_pairs_to_hash_map(self.ports),
+            env: env_pairs_to_hash_map(self.envs),
+            unset_env: self.unset_envs,
+            ulimits: ulimits_to_hash_map(self.ulimits),
+            unset_ulimits: self.unset_ulimits,
+            command: if self.remove_command {
+                Some(String::new())
+            } else {
+                self.command
+            },
+            args: self.args,
+            krun_log_level: self.krun_log_level,
         };
 
-        if let Some(cpus) = self.cpus {
-            if cpus > 8 {
-                println!("Error: the maximum number of CPUs supported is 8");
-            } else {
-                vmcfg.cpus = cpus;
-                cfg_changed = true;
-            }
-        }
-
-        if let Some(mem) = self.mem {
-            if mem > 16384 {
-                println!("Error: the maximum amount of RAM supported is 16384 MiB");
-            } else {
-                vmcfg.mem = mem;
-                cfg_changed = true;
-            }
-        }
-
-        if self.remove_volumes {
-            vmcfg.mapped_volumes = HashMap::new();
-            cfg_changed = true;
-        } else {
-            let mapped_volumes = path_pairs_to_hash_map(self.volumes);
-
-            if !mapped_volumes.is_empty() {
-                vmcfg.mapped_volumes = mapped_volumes;
-                cfg_changed = true;
-            }
-        }
-        // TODO: don't just silently ignore --volume args when --remove_volumes is specified
-
-        if self.remove_ports {
-            vmcfg.mapped_ports = HashMap::new();
-            cfg_changed = true;
-        } else {
-            let mapped_ports = port_pairs_to_hash_map(self.ports);
-
-            if !mapped_ports.is_empty() {
-                vmcfg.mapped_ports = mapped_ports;
-                cfg_changed = true;
-            }
-        }
-        // TODO: don't just silently ignore --port args when --remove_ports is specified
-
-        if let Some(workdir) = self.workdir {
-            vmcfg.workdir = workdir.to_string();
-            cfg_changed = true;
-        }
+        let vmcfg = change_vm(cfg, &self.name, opts)?;
 
         println!();
-        printvm(vmcfg);
+        printvm(vmcfg, None);
         println!();
-
-        if cfg_changed {
-            confy::store(APP_NAME, &cfg).unwrap();
-        }
+        Ok(())
     }
 }
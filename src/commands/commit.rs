@@ -0,0 +1,26 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::Args;
+use krunvm::backend::SystemBackends;
+use krunvm::vm::commit_vm;
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// Save the root filesystem of a microVM as a new image
+#[derive(Args, Debug)]
+pub struct CommitCmd {
+    /// Name of the microVM to be committed
+    pub(crate) name: String,
+
+    /// Name of the image to be created
+    image: String,
+}
+
+impl CommitCmd {
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
+        commit_vm(cfg, &SystemBackends, &self.name, &self.image)?;
+
+        println!("microVM {} committed as image {}", self.name, self.image);
+        Ok(())
+    }
+}
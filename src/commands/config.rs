@@ -1,8 +1,11 @@
 // Copyright 2021 Red Hat, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{KrunvmConfig, APP_NAME};
 use clap::Args;
+use krunvm::backend::{validate_backend, DEFAULT_BACKEND};
+use krunvm::storage::store_config;
+use krunvm::vm::{validate_cpus, validate_mem};
+use krunvm::{KrunvmConfig, KrunvmError};
 
 /// Configure global values
 #[derive(Args, Debug)]
@@ -18,28 +21,93 @@ pub struct ConfigCmd {
     /// DNS server to use in the microVM
     #[arg(long)]
     dns: Option<String>,
+
+    /// Container backend to use for newly created VMs
+    #[arg(long)]
+    backend: Option<String>,
+
+    /// Storage root directory for buildah, "" to use its default
+    #[arg(long)]
+    #[cfg(target_os = "linux")]
+    buildah_root: Option<String>,
+
+    /// Storage state directory for buildah, "" to use its default
+    #[arg(long)]
+    #[cfg(target_os = "linux")]
+    buildah_runroot: Option<String>,
+
+    /// Storage driver for buildah, "" to use its default
+    #[arg(long)]
+    #[cfg(target_os = "linux")]
+    buildah_storage_driver: Option<String>,
+
+    /// Registries configuration file for buildah, "" to use its default
+    #[arg(long)]
+    #[cfg(target_os = "linux")]
+    buildah_registries_conf: Option<String>,
+
+    /// Signature policy file for buildah, "" to use its default
+    #[arg(long)]
+    #[cfg(target_os = "linux")]
+    buildah_signature_policy: Option<String>,
+}
+
+/// Checks `path`, if set, is absolute, as buildah may run from a different
+/// directory.
+#[cfg(target_os = "linux")]
+fn validate_path(opt: &str, path: String) -> Result<String, KrunvmError> {
+    if !path.is_empty() && !std::path::Path::new(&path).is_absolute() {
+        return Err(KrunvmError::InvalidInput(format!(
+            "The path given to --{} must be absolute",
+            opt
+        )));
+    }
+    Ok(path)
+}
+
+/// Checks the storage of buildah can be moved from `current` to `value`
+/// with `opt`, which isn't the case while VMs have their containers in it.
+#[cfg(target_os = "linux")]
+fn check_storage_unused(
+    cfg: &KrunvmConfig,
+    opt: &str,
+    current: &str,
+    value: &str,
+) -> Result<(), KrunvmError> {
+    if current == value {
+        return Ok(());
+    }
+
+    let mut vms: Vec<&str> = cfg
+        .vmconfig_map
+        .values()
+        .filter(|vm| vm.backend.is_empty() || vm.backend == DEFAULT_BACKEND)
+        .map(|vm| vm.name.as_str())
+        .collect();
+    if vms.is_empty() {
+        return Ok(());
+    }
+    vms.sort_unstable();
+    Err(KrunvmError::InvalidInput(format!(
+        "Can't change --{} while the following microVMs have their containers in the \
+         storage of buildah: {}. Delete them first",
+        opt,
+        vms.join(", ")
+    )))
 }
 
 impl ConfigCmd {
-    pub fn run(self, cfg: &mut KrunvmConfig) {
+    pub fn run(self, cfg: &mut KrunvmConfig) -> Result<(), KrunvmError> {
         let mut cfg_changed = false;
 
         if let Some(cpus) = self.cpus {
-            if cpus > 8 {
-                println!("Error: the maximum number of CPUs supported is 8");
-            } else {
-                cfg.default_cpus = cpus;
-                cfg_changed = true;
-            }
+            cfg.default_cpus = validate_cpus(cpus)?;
+            cfg_changed = true;
         }
 
         if let Some(mem) = self.mem {
-            if mem > 16384 {
-                println!("Error: the maximum amount of RAM supported is 16384 MiB");
-            } else {
-                cfg.default_mem = mem;
-                cfg_changed = true;
-            }
+            cfg.default_mem = validate_mem(mem)?;
+            cfg_changed = true;
         }
 
         if let Some(dns) = self.dns {
@@ -47,8 +115,68 @@ impl ConfigCmd {
             cfg_changed = true;
         }
 
+        if let Some(backend) = self.backend {
+            validate_backend(&backend)?;
+            cfg.backend = backend;
+            cfg_changed = true;
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            // Containers can't follow their storage, so it can only be
+            // changed while no VM uses it.
+            let storage = [
+                ("buildah-root", &self.buildah_root, &cfg.buildah_root),
+                (
+                    "buildah-runroot",
+                    &self.buildah_runroot,
+                    &cfg.buildah_runroot,
+                ),
+                (
+                    "buildah-storage-driver",
+                    &self.buildah_storage_driver,
+                    &cfg.buildah_storage_driver,
+                ),
+            ];
+            for (opt, value, current) in storage {
+                if let Some(value) = value {
+                    check_storage_unused(cfg, opt, current, value)?;
+                }
+            }
+
+            let paths = [
+                ("buildah-root", self.buildah_root, &mut cfg.buildah_root),
+                (
+                    "buildah-runroot",
+                    self.buildah_runroot,
+                    &mut cfg.buildah_runroot,
+                ),
+                (
+                    "buildah-registries-conf",
+                    self.buildah_registries_conf,
+                    &mut cfg.buildah_registries_conf,
+                ),
+                (
+                    "buildah-signature-policy",
+                    self.buildah_signature_policy,
+                    &mut cfg.buildah_signature_policy,
+                ),
+            ];
+            for (opt, value, field) in paths {
+                if let Some(value) = value {
+                    *field = validate_path(opt, value)?;
+                    cfg_changed = true;
+                }
+            }
+
+            if let Some(storage_driver) = self.buildah_storage_driver {
+                cfg.buildah_storage_driver = storage_driver;
+                cfg_changed = true;
+            }
+        }
+
         if cfg_changed {
-            confy::store(APP_NAME, &cfg).unwrap();
+            store_config(cfg).map_err(KrunvmError::Config)?;
         }
 
         println!("Global configuration:");
@@ -64,5 +192,25 @@ impl ConfigCmd {
             "Default DNS server for newly created VMs: {}",
             cfg.default_dns
         );
+        println!("Container backend for newly created VMs: {}", cfg.backend);
+        #[cfg(target_os = "linux")]
+        {
+            let buildah_opts = [
+                ("Buildah storage root", &cfg.buildah_root),
+                ("Buildah storage runroot", &cfg.buildah_runroot),
+                ("Buildah storage driver", &cfg.buildah_storage_driver),
+                (
+                    "Buildah registries configuration",
+                    &cfg.buildah_registries_conf,
+                ),
+                ("Buildah signature policy", &cfg.buildah_signature_policy),
+            ];
+            for (desc, value) in buildah_opts {
+                if !value.is_empty() {
+                    println!("{}: {}", desc, value);
+                }
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,58 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::env;
+
+use clap::{Args, Subcommand};
+use krunvm::context::{active_context, list_contexts, use_context, CONFIG_ENV};
+use krunvm::KrunvmError;
+
+/// Manage configuration contexts
+#[derive(Args, Debug)]
+pub struct ContextCmd {
+    #[command(subcommand)]
+    command: ContextCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ContextCommand {
+    /// List the existing contexts, marking the active one
+    List,
+    /// Switch to a context, creating it if it doesn't exist
+    Use {
+        /// Name of the context ("default" for the default configuration)
+        name: String,
+    },
+}
+
+impl ContextCmd {
+    pub fn run(self, config_overridden: bool) -> Result<(), KrunvmError> {
+        match self.command {
+            ContextCommand::List => {
+                let active = active_context().map_err(KrunvmError::Config)?;
+                for name in list_contexts().map_err(KrunvmError::Config)? {
+                    let marker = if name == active { "*" } else { " " };
+                    println!("{} {}", marker, name);
+                }
+            }
+            ContextCommand::Use { name } => {
+                let dir = use_context(&name).map_err(|err| {
+                    if err.kind() == std::io::ErrorKind::InvalidInput {
+                        KrunvmError::InvalidInput(format!("Invalid context name: {}", name))
+                    } else {
+                        KrunvmError::Config(err)
+                    }
+                })?;
+                println!("Switched to context {} ({})", name, dir.display());
+            }
+        }
+
+        if config_overridden || env::var_os(CONFIG_ENV).is_some_and(|dir| !dir.is_empty()) {
+            println!(
+                "Note: the active context is ignored while --config or {} are set",
+                CONFIG_ENV
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::Args;
+use krunvm::agent::ExecRequest;
+use krunvm::utils::{env_pairs_to_hash_map, EnvPair};
+use krunvm::vm::exec_in_vm;
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// Run a command in a running microVM
+#[derive(Args, Debug)]
+pub struct ExecCmd {
+    /// Name of the microVM
+    name: String,
+
+    /// Command to run, followed by its arguments
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+
+    /// env(s) in format "key=value" to be added to the environment of the command
+    #[arg(long = "env")]
+    envs: Vec<EnvPair>,
+
+    /// Working directory of the command
+    #[arg(short, long)]
+    workdir: Option<String>,
+}
+
+impl ExecCmd {
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
+        let mut env: Vec<String> = env_pairs_to_hash_map(self.envs)
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        env.sort();
+
+        let request = ExecRequest {
+            command: self.command,
+            env,
+            workdir: self.workdir.unwrap_or_default(),
+        };
+
+        let code = exec_in_vm(cfg, &self.name, &request)?;
+        if code != 0 {
+            std::process::exit(code);
+        }
+        Ok(())
+    }
+}
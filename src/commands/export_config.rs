@@ -0,0 +1,39 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs;
+use std::path::PathBuf;
+
+use clap::Args;
+use krunvm::vm::export_vm;
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// Export the definition of a microVM
+#[derive(Args, Debug)]
+pub struct ExportConfigCmd {
+    /// Name of the microVM to be exported
+    name: String,
+
+    /// Write the definition to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// OCI image to record, for microVMs created by older versions of krunvm
+    #[arg(long)]
+    image: Option<String>,
+}
+
+impl ExportConfigCmd {
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
+        let definition = export_vm(cfg, &self.name, self.image)?;
+
+        match self.output {
+            Some(path) => fs::write(&path, definition)
+                .map_err(|err| KrunvmError::Io(format!("writing {}", path.display()), err)),
+            None => {
+                print!("{}", definition);
+                Ok(())
+            }
+        }
+    }
+}
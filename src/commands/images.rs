@@ -0,0 +1,36 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::Args;
+use krunvm::backend::SystemBackends;
+use krunvm::image::list_images;
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// List the images in local storage
+#[derive(Args, Debug)]
+pub struct ImagesCmd {}
+
+impl ImagesCmd {
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
+        let images = list_images(cfg, &SystemBackends)?;
+
+        if images.is_empty() {
+            println!("No images found");
+            return Ok(());
+        }
+
+        for usage in images.iter() {
+            println!();
+            if usage.image.names.is_empty() {
+                println!("<none>");
+            } else {
+                println!("{}", usage.image.names.join(", "));
+            }
+            println!(" ID: {}", usage.image.id);
+            println!(" Size: {}", usage.image.size);
+            println!(" Used by: {}", usage.vms.join(", "));
+        }
+        println!();
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use krunvm::backend::SystemBackends;
+use krunvm::vm::{get_vm, import_vm};
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// Create a microVM from an exported definition
+#[derive(Args, Debug)]
+pub struct ImportConfigCmd {
+    /// File containing the definition, as written by export-config
+    file: PathBuf,
+
+    /// Assign a name to the VM instead of the exported one
+    #[arg(long)]
+    name: Option<String>,
+}
+
+impl ImportConfigCmd {
+    pub fn run(self, cfg: &mut KrunvmConfig) -> Result<(), KrunvmError> {
+        let data = fs::read_to_string(&self.file)
+            .map_err(|err| KrunvmError::Io(format!("reading {}", self.file.display()), err))?;
+
+        let name = import_vm(cfg, &SystemBackends, &data, self.name)?;
+
+        for host_path in get_vm(cfg, &name)?.mapped_volumes.keys() {
+            if !Path::new(host_path).exists() {
+                println!(
+                    "Warning: volume host_path {} does not exist on this system",
+                    host_path
+                );
+            }
+        }
+
+        println!("microVM created with name: {}", name);
+        Ok(())
+    }
+}
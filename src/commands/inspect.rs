@@ -1,52 +1,59 @@
 // Copyright 2021 Red Hat, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::process::Command;
-
-use crate::{
-    utils::{get_buildah_args, BuildahCommand},
-    KrunvmConfig,
-};
 use clap::Args;
+use krunvm::backend::SystemBackends;
+use krunvm::image::InspectInfo;
+use krunvm::vm::{inspect_vm, vm_state};
+use krunvm::{KrunvmConfig, KrunvmError};
+
+use super::list::{format_started, format_status};
 
-/// Run `buildah inspect` on an existing microVM
+/// Show the runtime status and image configuration of an existing microVM
 #[derive(Args, Debug)]
 pub struct InspectCmd {
     /// Name of the microVM to be inspected
-    name: String,
+    pub(crate) name: String,
+
+    /// Print the unprocessed output of `buildah inspect`
+    #[arg(long)]
+    raw: bool,
 }
 
 impl InspectCmd {
-    pub fn run(self, cfg: &mut KrunvmConfig) {
-        let vmcfg = match cfg.vmconfig_map.get(&self.name) {
-            None => {
-                println!("No VM found with that name");
-                std::process::exit(-1);
-            }
-            Some(vmcfg) => vmcfg,
-        };
-
-        let mut args = get_buildah_args(cfg, BuildahCommand::Inspect);
-        args.push(vmcfg.container.clone());
-
-        let output = Command::new("buildah")
-            .args(&args)
-            .stderr(std::process::Stdio::inherit())
-            .output();
-
-        if output.is_err() {
-            println!("Failed to inspect VM");
-            std::process::exit(1);
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
+        let output = inspect_vm(cfg, &SystemBackends, &self.name)?;
+
+        if self.raw {
+            println!("{output}");
+            return Ok(());
         }
 
-        let output = match String::from_utf8(output.unwrap().stdout) {
-            Err(err) => {
-                println!("Failed to parse `buildah inspect` output: #{err}.");
-                std::process::exit(1);
-            }
-            Ok(output) => output,
-        };
+        let info = InspectInfo::parse(&output)?;
+        let config = &info.image.config;
 
-        println!("{output}");
+        let state = vm_state(cfg, &self.name)?;
+
+        println!("{}", self.name);
+        println!(" Status: {}", format_status(&state));
+        if state.started_at != 0 {
+            println!(" Last started: {}", format_started(&state));
+            println!(" Last command: {:?}", state.command);
+        }
+        println!(" Image: {}", info.from_image);
+        if !info.from_image_digest.is_empty() {
+            println!(" Digest: {}", info.from_image_digest);
+        }
+        println!(" Container: {}", info.container);
+        println!(" Platform: {}/{}", info.image.os, info.image.architecture);
+        println!(" Entrypoint: {:?}", config.entrypoint);
+        println!(" Cmd: {:?}", config.cmd);
+        println!(" Workdir: {}", config.working_dir);
+        println!(" User: {}", config.user);
+        println!(" Environment: {:?}", config.env);
+        println!(" Exposed ports: {:?}", config.exposed_ports);
+        println!(" Volumes: {:?}", config.volumes);
+        println!(" Labels: {:?}", config.labels);
+        Ok(())
     }
 }
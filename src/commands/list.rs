@@ -1,38 +1,96 @@
 // Copyright 2021 Red Hat, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{KrunvmConfig, VmConfig};
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use clap::Args;
+use krunvm::backend::ROOTFS_BACKEND;
+use krunvm::vm::vm_state;
+use krunvm::{KrunvmConfig, KrunvmError, VmConfig, VmState, VmStatus};
 
 /// List microVMs
 #[derive(Args, Debug)]
-pub struct ListCmd {
-    /// Print debug information verbosely
-    #[arg(short)]
-    pub debug: bool, //TODO: implement or remove this
-}
+pub struct ListCmd {}
 
 impl ListCmd {
-    pub fn run(self, cfg: &KrunvmConfig) {
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
         if cfg.vmconfig_map.is_empty() {
             println!("No microVMs found");
         } else {
-            for (_name, vm) in cfg.vmconfig_map.iter() {
+            for (name, vm) in cfg.vmconfig_map.iter() {
                 println!();
-                printvm(vm);
+                printvm(vm, Some(&vm_state(cfg, name)?));
             }
             println!();
         }
+        Ok(())
+    }
+}
+
+/// Formats a duration in seconds with its two most significant units.
+fn format_duration(secs: u64) -> String {
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Returns how long ago the VM was last started.
+pub fn format_started(state: &VmState) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    format!(
+        "{} ago",
+        format_duration(now.saturating_sub(state.started_at))
+    )
+}
+
+pub fn format_status(state: &VmState) -> String {
+    match (state.status, state.exit_status) {
+        (VmStatus::Running, _) => format!(
+            "{} (pid {}, started {})",
+            state.status,
+            state.pid,
+            format_started(state)
+        ),
+        (status, Some(code)) => format!("{} (exit status {})", status, code),
+        (status, None) => status.to_string(),
     }
 }
 
-pub fn printvm(vm: &VmConfig) {
+pub fn printvm(vm: &VmConfig, state: Option<&VmState>) {
     println!("{}", vm.name);
+    if let Some(state) = state {
+        println!(" Status: {}", format_status(state));
+    }
+    if !vm.image.is_empty() {
+        println!(" Image: {}", vm.image);
+    }
     println!(" CPUs: {}", vm.cpus);
     println!(" RAM (MiB): {}", vm.mem);
     println!(" DNS server: {}", vm.dns);
-    println!(" Buildah container: {}", vm.container);
+    if vm.backend == ROOTFS_BACKEND {
+        println!(" Root filesystem: {}", vm.container);
+    } else {
+        println!(" Buildah container: {}", vm.container);
+    }
     println!(" Workdir: {}", vm.workdir);
     println!(" Mapped volumes: {:?}", vm.mapped_volumes);
     println!(" Mapped ports: {:?}", vm.mapped_ports);
+    println!(" Environment: {:?}", vm.env);
+    println!(" Ulimits: {:?}", vm.ulimits);
+    if vm.args.is_empty() {
+        println!(" Command: {}", vm.command);
+    } else {
+        println!(" Command: {} {:?}", vm.command, vm.args);
+    }
+    println!(" libkrun log level: {}", vm.krun_log_level);
 }
@@ -0,0 +1,44 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+
+use clap::Args;
+use krunvm::vm::{parse_since, read_logs, LogOptions};
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// Show the console output of a microVM started with --detach
+#[derive(Args, Debug)]
+pub struct LogsCmd {
+    /// Name of the microVM
+    name: String,
+
+    /// Keep printing new output until the microVM stops
+    #[arg(short, long)]
+    follow: bool,
+
+    /// Only print the last NUM lines
+    #[arg(short = 'n', long, value_name = "NUM")]
+    tail: Option<usize>,
+
+    /// Only print output since a time, either relative (10m, 1h30m) or absolute (2021-01-31T12:00:00Z)
+    #[arg(long, value_name = "TIME")]
+    since: Option<String>,
+
+    /// Print the time each line was written at
+    #[arg(short, long)]
+    timestamps: bool,
+}
+
+impl LogsCmd {
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
+        let opts = LogOptions {
+            follow: self.follow,
+            tail: self.tail,
+            since: self.since.as_deref().map(parse_since).transpose()?,
+            timestamps: self.timestamps,
+        };
+
+        read_logs(cfg, &self.name, &opts, &mut io::stdout().lock())
+    }
+}
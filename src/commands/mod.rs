@@ -1,15 +1,37 @@
+mod apply;
 mod changevm;
+mod commit;
 mod config;
+mod context;
 mod create;
 mod delete;
+mod exec;
+mod export_config;
+mod images;
+mod import_config;
 mod inspect;
 mod list;
+mod logs;
+mod pull;
+mod rmi;
 mod start;
+mod stop;
 
+pub use apply::ApplyCmd;
 pub use changevm::ChangeVmCmd;
+pub use commit::CommitCmd;
 pub use config::ConfigCmd;
+pub use context::ContextCmd;
 pub use create::CreateCmd;
 pub use delete::DeleteCmd;
+pub use exec::ExecCmd;
+pub use export_config::ExportConfigCmd;
+pub use images::ImagesCmd;
+pub use import_config::ImportConfigCmd;
 pub use inspect::InspectCmd;
 pub use list::ListCmd;
+pub use logs::LogsCmd;
+pub use pull::PullCmd;
+pub use rmi::RmiCmd;
 pub use start::StartCmd;
+pub use stop::StopCmd;
@@ -0,0 +1,23 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::Args;
+use krunvm::backend::SystemBackends;
+use krunvm::image::pull_image;
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// Pull an image into local storage
+#[derive(Args, Debug)]
+pub struct PullCmd {
+    /// OCI image to pull
+    image: String,
+}
+
+impl PullCmd {
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
+        let id = pull_image(cfg, &SystemBackends, &self.image)?;
+
+        println!("Image {} pulled with ID: {}", self.image, id);
+        Ok(())
+    }
+}
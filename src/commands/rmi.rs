@@ -0,0 +1,34 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use clap::Args;
+use krunvm::backend::SystemBackends;
+use krunvm::image::remove_image;
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// Remove an image from local storage
+#[derive(Args, Debug)]
+pub struct RmiCmd {
+    /// Name or ID of the image to be removed
+    image: String,
+
+    /// Remove the image even if microVMs, which are left unusable, or containers not managed by
+    /// krunvm use it
+    #[arg(short, long)]
+    force: bool,
+}
+
+impl RmiCmd {
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
+        let broken = remove_image(cfg, &SystemBackends, &self.image, self.force)?;
+
+        println!("Image {} removed", self.image);
+        if !broken.is_empty() {
+            println!(
+                "The following microVMs can no longer be started and should be deleted: {}",
+                broken.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
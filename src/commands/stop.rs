@@ -0,0 +1,29 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::Duration;
+
+use clap::Args;
+use krunvm::vm::stop_vm;
+use krunvm::{KrunvmConfig, KrunvmError};
+
+/// Stop a running microVM
+#[derive(Args, Debug)]
+pub struct StopCmd {
+    /// Name of the microVM to be stopped
+    name: String,
+
+    /// Seconds to wait for the microVM to shut down before killing it
+    #[arg(short, long, default_value_t = 10)]
+    timeout: u64,
+}
+
+impl StopCmd {
+    pub fn run(self, cfg: &KrunvmConfig) -> Result<(), KrunvmError> {
+        for warning in stop_vm(cfg, &self.name, Duration::from_secs(self.timeout))? {
+            println!("{}", warning);
+        }
+        println!("{}", self.name);
+        Ok(())
+    }
+}
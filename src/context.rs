@@ -0,0 +1,97 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::storage::default_config_dir;
+
+/// Environment variable selecting the configuration directory, taking
+/// precedence over the active context.
+pub const CONFIG_ENV: &str = "KRUNVM_CONFIG";
+
+/// Name of the context using the default configuration directory.
+pub const DEFAULT_CONTEXT: &str = "default";
+
+const CONTEXTS_DIR: &str = "contexts";
+const ACTIVE_CONTEXT_FILE: &str = "context";
+
+fn is_valid_context_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/')
+}
+
+/// Returns the configuration directory of the context named `name`.
+pub fn context_dir(name: &str) -> Result<PathBuf, Error> {
+    if !is_valid_context_name(name) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid context name: {}", name),
+        ));
+    }
+
+    let dir = default_config_dir()?;
+    if name == DEFAULT_CONTEXT {
+        Ok(dir)
+    } else {
+        Ok(dir.join(CONTEXTS_DIR).join(name))
+    }
+}
+
+/// Returns the name of the active context.
+pub fn active_context() -> Result<String, Error> {
+    let path = default_config_dir()?.join(ACTIVE_CONTEXT_FILE);
+    match fs::read_to_string(path) {
+        Ok(name) if !name.trim().is_empty() => Ok(name.trim().to_string()),
+        Ok(_) => Ok(DEFAULT_CONTEXT.to_string()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(DEFAULT_CONTEXT.to_string()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Makes `name` the active context, creating its configuration directory
+/// if it doesn't exist yet.
+pub fn use_context(name: &str) -> Result<PathBuf, Error> {
+    let dir = context_dir(name)?;
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        default_config_dir()?.join(ACTIVE_CONTEXT_FILE),
+        format!("{}\n", name),
+    )?;
+    Ok(dir)
+}
+
+/// Returns the names of the existing contexts, including the default one.
+pub fn list_contexts() -> Result<Vec<String>, Error> {
+    let mut contexts = vec![DEFAULT_CONTEXT.to_string()];
+
+    match fs::read_dir(default_config_dir()?.join(CONTEXTS_DIR)) {
+        Ok(entries) => {
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    contexts.push(entry.file_name().to_string_lossy().to_string());
+                }
+            }
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    contexts[1..].sort();
+    Ok(contexts)
+}
+
+/// Returns the configuration directory to be used, in order of
+/// precedence: `explicit` (usually from the command line), the
+/// `KRUNVM_CONFIG` environment variable, and the active context.
+pub fn resolve_config_dir(explicit: Option<&Path>) -> Result<PathBuf, Error> {
+    if let Some(dir) = explicit {
+        return Ok(dir.to_path_buf());
+    }
+    if let Some(dir) = env::var_os(CONFIG_ENV).filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+    context_dir(&active_context()?)
+}
@@ -0,0 +1,100 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::io;
+
+/// Ways in which running buildah can fail.
+#[derive(Debug)]
+pub enum BuildahError {
+    /// The buildah binary couldn't be found.
+    NotInstalled,
+    /// buildah couldn't be executed.
+    Spawn(io::Error),
+    /// buildah exited with an error, along with the command line and what
+    /// it wrote to stderr.
+    Failed {
+        command: String,
+        code: i32,
+        stderr: String,
+    },
+    /// The output of buildah couldn't be parsed.
+    Output(String),
+}
+
+impl fmt::Display for BuildahError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildahError::NotInstalled => write!(
+                f,
+                "{} requires buildah to manage the OCI images, and it wasn't found on this system.",
+                crate::APP_NAME
+            ),
+            BuildahError::Spawn(err) => write!(f, "Error executing buildah: {}", err),
+            BuildahError::Failed {
+                command,
+                code,
+                stderr,
+            } => write!(
+                f,
+                "`{}` failed with exit code {}: {}",
+                command, code, stderr
+            ),
+            BuildahError::Output(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KrunvmError {
+    /// An invocation of buildah failed.
+    Buildah(BuildahError),
+    /// The container backing a VM doesn't exist anymore.
+    ContainerNotFound(String),
+    /// A libkrun call returned an error.
+    Libkrun { op: &'static str, code: i32 },
+    /// The process running a VM failed to set it up, for the given reason.
+    VmSetup(String),
+    /// No VM is registered with the given name.
+    VmNotFound(String),
+    /// The arguments provided by the user are not valid.
+    InvalidInput(String),
+    /// Loading or storing the krunvm configuration failed.
+    Config(io::Error),
+    /// Any other I/O error, along with a description of the operation.
+    Io(String, io::Error),
+}
+
+impl fmt::Display for KrunvmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KrunvmError::Buildah(err) => write!(f, "{}", err),
+            KrunvmError::ContainerNotFound(container) => {
+                write!(f, "Container {} not found", container)
+            }
+            KrunvmError::Libkrun { op, code } => write!(
+                f,
+                "Error {}: {}",
+                op,
+                io::Error::from_raw_os_error(code.abs())
+            ),
+            KrunvmError::VmSetup(reason) => write!(f, "Failed to set up the microVM: {}", reason),
+            KrunvmError::VmNotFound(name) => write!(f, "No VM found with name {}", name),
+            KrunvmError::InvalidInput(msg) => write!(f, "{}", msg),
+            KrunvmError::Config(err) => {
+                write!(f, "Error accessing the krunvm configuration: {}", err)
+            }
+            KrunvmError::Io(op, err) => write!(f, "Error {}: {}", op, err),
+        }
+    }
+}
+
+impl std::error::Error for KrunvmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KrunvmError::Config(err) | KrunvmError::Io(_, err) => Some(err),
+            KrunvmError::Buildah(BuildahError::Spawn(err)) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer};
+use serde_derive::Deserialize;
+
+use crate::backend::{Backends, ImageSummary, DEFAULT_BACKEND};
+use crate::vm::vm_state;
+use crate::{BuildahError, KrunvmConfig, KrunvmError, VmConfig, VmStatus};
+
+/// Deserializes a value that may be `null`, as Go's encoding/json writes
+/// empty slices and maps, into its default.
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Deserializes a JSON object used as a set, such as `ExposedPorts`, into
+/// its keys.
+fn set_keys<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let map: Option<BTreeMap<String, serde::de::IgnoredAny>> = Option::deserialize(deserializer)?;
+    Ok(map.unwrap_or_default().into_keys().collect())
+}
+
+/// Output of `buildah inspect`, for either an image or a container,
+/// restricted to the fields krunvm cares about.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct InspectInfo {
+    /// Reference of the image, or of the image the container was created
+    /// from.
+    pub from_image: String,
+    #[serde(rename = "FromImageID")]
+    pub from_image_id: String,
+    pub from_image_digest: String,
+    /// Name of the container, empty when inspecting an image.
+    pub container: String,
+    #[serde(rename = "OCIv1")]
+    pub image: ImageSpec,
+}
+
+/// An OCI image configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ImageSpec {
+    pub architecture: String,
+    pub os: String,
+    pub config: ImageConfig,
+}
+
+/// Execution parameters of an OCI image.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct ImageConfig {
+    pub user: String,
+    #[serde(deserialize_with = "set_keys")]
+    pub exposed_ports: Vec<String>,
+    #[serde(deserialize_with = "nullable")]
+    pub env: Vec<String>,
+    #[serde(deserialize_with = "nullable")]
+    pub entrypoint: Vec<String>,
+    #[serde(deserialize_with = "nullable")]
+    pub cmd: Vec<String>,
+    #[serde(deserialize_with = "set_keys")]
+    pub volumes: Vec<String>,
+    pub working_dir: String,
+    #[serde(deserialize_with = "nullable")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl InspectInfo {
+    pub fn parse(data: &str) -> Result<InspectInfo, KrunvmError> {
+        serde_json::from_str(data).map_err(|err| {
+            KrunvmError::Buildah(BuildahError::Output(format!(
+                "Failed to parse `buildah inspect` output: {}",
+                err
+            )))
+        })
+    }
+}
+
+/// An image in the storage of the default backend, along with the VMs
+/// created from it.
+#[derive(Debug)]
+pub struct ImageUsage {
+    pub image: ImageSummary,
+    pub vms: Vec<String>,
+}
+
+/// Returns `reference` with the tag buildah assumes when it has none.
+fn with_default_tag(reference: &str) -> String {
+    let last = reference.rsplit('/').next().unwrap_or(reference);
+    if reference.contains('@') || last.contains(':') {
+        reference.to_string()
+    } else {
+        format!("{}:latest", reference)
+    }
+}
+
+/// Shortest prefix of an image ID accepted as a reference to the image.
+const MIN_ID_PREFIX: usize = 4;
+
+/// Checks whether `reference`, as given by the user, names `image`. Short
+/// names, lacking the registry or the tag, match the fully qualified names
+/// recorded by buildah.
+fn name_matches(image: &ImageSummary, reference: &str) -> bool {
+    if reference.is_empty() {
+        return false;
+    }
+    let reference = with_default_tag(reference);
+    let suffix = format!("/{}", reference);
+    image
+        .names
+        .iter()
+        .any(|name| *name == reference || name.ends_with(&suffix))
+}
+
+/// Returns the images `reference`, as given by the user, points to. Names
+/// take precedence, and only when none matches is `reference` taken as a
+/// prefix of an image ID.
+pub fn find_images<'a>(images: &'a [ImageSummary], reference: &str) -> Vec<&'a ImageSummary> {
+    let by_name: Vec<&ImageSummary> = images
+        .iter()
+        .filter(|image| name_matches(image, reference))
+        .collect();
+    if !by_name.is_empty()
+        || reference.len() < MIN_ID_PREFIX
+        || !reference.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return by_name;
+    }
+    images
+        .iter()
+        .filter(|image| image.id.starts_with(reference))
+        .collect()
+}
+
+/// Checks whether `vm` was created from `image`, one of `images`. VMs
+/// created before their image ID was recorded are matched by the
+/// reference to their image.
+pub fn vm_uses_image(vm: &VmConfig, images: &[ImageSummary], image: &ImageSummary) -> bool {
+    if vm.image_id.is_empty() {
+        find_images(images, &vm.image)
+            .iter()
+            .any(|candidate| candidate.id == image.id)
+    } else {
+        vm.image_id == image.id
+    }
+}
+
+/// Returns the names of the VMs of the default backend created from
+/// `image`, one of the `images` in its storage.
+fn vms_using(cfg: &KrunvmConfig, images: &[ImageSummary], image: &ImageSummary) -> Vec<String> {
+    // VMs created before backends were selectable don't record theirs.
+    let backend_name = |name: &str| {
+        if name.is_empty() {
+            DEFAULT_BACKEND.to_string()
+        } else {
+            name.to_string()
+        }
+    };
+    let backend = backend_name(&cfg.backend);
+
+    let mut vms: Vec<String> = cfg
+        .vmconfig_map
+        .values()
+        .filter(|vm| backend_name(&vm.backend) == backend)
+        .filter(|vm| vm_uses_image(vm, images, image))
+        .map(|vm| vm.name.clone())
+        .collect();
+    vms.sort();
+    vms
+}
+
+/// Returns the images in the storage of the default backend.
+pub fn list_images(
+    cfg: &KrunvmConfig,
+    backends: &dyn Backends,
+) -> Result<Vec<ImageUsage>, KrunvmError> {
+    let images = backends.default_backend(cfg)?.images()?;
+    Ok(images
+        .iter()
+        .map(|image| ImageUsage {
+            vms: vms_using(cfg, &images, image),
+            image: image.clone(),
+        })
+        .collect())
+}
+
+/// Fetches `image` into the storage of the default backend, returning its
+/// ID.
+pub fn pull_image(
+    cfg: &KrunvmConfig,
+    backends: &dyn Backends,
+    image: &str,
+) -> Result<String, KrunvmError> {
+    backends.default_backend(cfg)?.pull(image)
+}
+
+/// Removes `image` from the storage of the default backend. Images VMs
+/// were created from are only removed with `force`, which also removes the
+/// containers of those VMs, as long as none of them is running, and the
+/// ones krunvm doesn't know about.
+///
+/// Returns the names of the VMs whose containers were removed, which can
+/// no longer be started and are only left to be deleted.
+pub fn remove_image(
+    cfg: &KrunvmConfig,
+    backends: &dyn Backends,
+    image: &str,
+    force: bool,
+) -> Result<Vec<String>, KrunvmError> {
+    let backend = backends.default_backend(cfg)?;
+
+    let images = backend.images()?;
+    let mut vms: Vec<String> = find_images(&images, image)
+        .into_iter()
+        .flat_map(|summary| vms_using(cfg, &images, summary))
+        .collect();
+    vms.sort();
+    vms.dedup();
+
+    if !vms.is_empty() && !force {
+        return Err(KrunvmError::InvalidInput(format!(
+            "Image {} is used by the following microVMs: {}. Delete them first, \
+             or use --force to remove it anyway",
+            image,
+            vms.join(", ")
+        )));
+    }
+
+    for name in vms.iter() {
+        if vm_state(cfg, name)?.status == VmStatus::Running {
+            return Err(KrunvmError::InvalidInput(format!(
+                "Image {} is used by microVM {}, which is running. Stop it first",
+                image, name
+            )));
+        }
+    }
+    for name in vms.iter() {
+        let container = &cfg.vmconfig_map[name].container;
+        let _ = backend.umount(container);
+        match backend.rm(container) {
+            Ok(()) | Err(KrunvmError::ContainerNotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    backend.rmi(image, force)?;
+    Ok(vms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{test_config, FakeBackend, TestDir};
+    use crate::vm::{create_vm, CreateOptions};
+
+    const ALPINE_ID: &str = "0123456789abcdef";
+    const FEDORA_ID: &str = "fedcba9876543210";
+
+    fn setup(dir: &TestDir) -> (KrunvmConfig, FakeBackend) {
+        let mut cfg = test_config(dir);
+        let backend = FakeBackend::new();
+        backend.add_image(ALPINE_ID, "docker.io/library/alpine:latest");
+        backend.add_image(FEDORA_ID, "registry.fedoraproject.org/fedora:latest");
+        let opts = CreateOptions {
+            image: "alpine".to_string(),
+            name: Some("vm".to_string()),
+            ..Default::default()
+        };
+        create_vm(&mut cfg, &backend, opts).unwrap();
+        (cfg, backend)
+    }
+
+    #[test]
+    fn finds_images_by_name_or_id_prefix() {
+        let image = |id: &str, name: &str| ImageSummary {
+            id: id.to_string(),
+            names: vec![name.to_string()],
+            size: String::new(),
+        };
+        let images = vec![
+            image(ALPINE_ID, "docker.io/library/alpine:3.14"),
+            image("cafe0123", "docker.io/library/debian:latest"),
+            image(FEDORA_ID, "docker.io/library/cafe:latest"),
+        ];
+        let find = |reference| -> Vec<&str> {
+            find_images(&images, reference)
+                .iter()
+                .map(|image| image.id.as_str())
+                .collect()
+        };
+
+        for reference in ["alpine:3.14", "library/alpine:3.14", "0123", ALPINE_ID].iter() {
+            assert_eq!(find(reference), vec![ALPINE_ID], "{}", reference);
+        }
+        // Names take precedence over ID prefixes.
+        assert_eq!(find("cafe"), vec![FEDORA_ID]);
+        assert_eq!(find("cafe0"), vec!["cafe0123"]);
+        for reference in ["", "alpine", "pine:3.14", "alpine:3", "012", "0123z"].iter() {
+            assert!(find(reference).is_empty(), "{} matched", reference);
+        }
+    }
+
+    #[test]
+    fn lists_vms_using_images() {
+        let dir = TestDir::new();
+        let (cfg, backend) = setup(&dir);
+
+        let images = list_images(&cfg, &backend).unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].image.id, ALPINE_ID);
+        assert_eq!(images[0].vms, vec!["vm".to_string()]);
+        assert!(images[1].vms.is_empty());
+    }
+
+    #[test]
+    fn refuses_removing_images_in_use() {
+        let dir = TestDir::new();
+        let (cfg, backend) = setup(&dir);
+
+        assert!(matches!(
+            remove_image(&cfg, &backend, "alpine", false),
+            Err(KrunvmError::InvalidInput(_))
+        ));
+        assert!(remove_image(&cfg, &backend, "fedora", false)
+            .unwrap()
+            .is_empty());
+        assert_eq!(backend.state.borrow().images.len(), 1);
+    }
+
+    #[test]
+    fn removes_containers_of_vms_when_forced() {
+        let dir = TestDir::new();
+        let (cfg, backend) = setup(&dir);
+
+        assert_eq!(
+            remove_image(&cfg, &backend, "alpine", true).unwrap(),
+            vec!["vm".to_string()]
+        );
+        let state = backend.state.borrow();
+        assert!(state.containers.is_empty());
+        assert_eq!(state.images.len(), 1);
+    }
+
+    #[test]
+    fn matches_vms_by_image_id() {
+        let dir = TestDir::new();
+        let (mut cfg, backend) = setup(&dir);
+        // The name now points to a different image than the VM was
+        // created from.
+        backend.state.borrow_mut().images[0].names.clear();
+        backend.add_image("aaaa", "docker.io/library/alpine:latest");
+
+        assert!(remove_image(&cfg, &backend, "alpine", false)
+            .unwrap()
+            .is_empty());
+        cfg.vmconfig_map.get_mut("vm").unwrap().image_id.clear();
+        assert!(list_images(&cfg, &backend).unwrap()[0].vms.is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use serde_derive::{Deserialize, Serialize};
+
+pub mod agent;
+pub mod backend;
+#[allow(unused)]
+mod bindings;
+pub mod context;
+mod error;
+pub mod image;
+pub mod manifest;
+pub mod migration;
+pub mod storage;
+pub mod trace;
+pub mod utils;
+pub mod vm;
+
+pub use error::{BuildahError, KrunvmError};
+
+pub const APP_NAME: &str = "krunvm";
+
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VmConfig {
+    pub name: String,
+    pub image: String,
+    /// ID `image` had when the VM was created. Empty for VMs created from
+    /// a root filesystem or by older versions of krunvm.
+    pub image_id: String,
+    pub cpus: u32,
+    pub mem: u32,
+    pub container: String,
+    /// Backend managing `container`. Empty for VMs created before backends
+    /// were selectable, which always used buildah.
+    pub backend: String,
+    pub workdir: String,
+    pub dns: String,
+    pub command: String,
+    pub args: Vec<String>,
+    /// Log level of libkrun, from 0 (off) to 5 (trace), unless overridden
+    /// when starting the VM.
+    pub krun_log_level: u32,
+    // Tables must come after plain values for the TOML serializer.
+    pub mapped_volumes: HashMap<String, String>,
+    pub mapped_ports: HashMap<String, String>,
+    pub env: HashMap<String, String>,
+    /// Resource limits of the guest, as "soft:hard" by resource name.
+    pub ulimits: HashMap<String, String>,
+}
+
+/// Runtime status of a VM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VmStatus {
+    Running,
+    #[default]
+    Stopped,
+    /// The VM was killed, or the process running it disappeared without
+    /// recording its exit.
+    Crashed,
+}
+
+impl fmt::Display for VmStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmStatus::Running => write!(f, "running"),
+            VmStatus::Stopped => write!(f, "stopped"),
+            VmStatus::Crashed => write!(f, "crashed"),
+        }
+    }
+}
+
+/// Runtime state of a VM, recorded by `start_vm` when the VM starts and
+/// exits.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VmState {
+    pub status: VmStatus,
+    /// Process running the VM.
+    pub pid: i32,
+    /// Start time, in seconds since the Unix epoch.
+    pub started_at: u64,
+    /// Exit code of the last run, or 128 plus the signal number if the VM
+    /// was killed.
+    pub exit_status: Option<i32>,
+    /// Set by `stop_vm`, so the VM being killed isn't taken as a crash.
+    pub stop_requested: bool,
+    /// Command and arguments run in the VM, empty if libkrun chose it.
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KrunvmConfig {
+    pub version: u8,
+    pub default_cpus: u32,
+    pub default_mem: u32,
+    pub default_dns: String,
+    pub storage_volume: String,
+    /// Options passed to every buildah invocation on Linux, to keep
+    /// krunvm's images and containers apart from the user's. Empty values
+    /// leave buildah's defaults in place.
+    pub buildah_root: String,
+    pub buildah_runroot: String,
+    pub buildah_storage_driver: String,
+    pub buildah_registries_conf: String,
+    pub buildah_signature_policy: String,
+    /// Container backend used for new VMs.
+    pub backend: String,
+    #[serde(skip_serializing)]
+    pub vmconfig_map: HashMap<String, VmConfig>,
+    /// Directory the configuration was loaded from, and will be stored to.
+    #[serde(skip)]
+    pub config_dir: PathBuf,
+}
+
+impl Default for KrunvmConfig {
+    fn default() -> KrunvmConfig {
+        KrunvmConfig {
+            version: migration::CONFIG_VERSION,
+            default_cpus: 2,
+            default_mem: 1024,
+            default_dns: "1.1.1.1".to_string(),
+            storage_volume: String::new(),
+            buildah_root: String::new(),
+            buildah_runroot: String::new(),
+            buildah_storage_driver: String::new(),
+            buildah_registries_conf: String::new(),
+            buildah_signature_policy: String::new(),
+            backend: backend::DEFAULT_BACKEND.to_string(),
+            vmconfig_map: HashMap::new(),
+            config_dir: PathBuf::new(),
+        }
+    }
+}
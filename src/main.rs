@@ -1,7 +1,6 @@
 // Copyright 2021 Red Hat, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
 #[cfg(target_os = "macos")]
 use std::env;
 #[cfg(target_os = "macos")]
@@ -12,58 +11,26 @@ use std::fs::File;
 use std::io::{self, Error, ErrorKind, Read, Write};
 #[cfg(target_os = "macos")]
 use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
 
 use crate::commands::{
-    ChangeVmCmd, ConfigCmd, CreateCmd, DeleteCmd, InspectCmd, ListCmd, StartCmd,
+    ApplyCmd, ChangeVmCmd, CommitCmd, ConfigCmd, ContextCmd, CreateCmd, DeleteCmd, ExecCmd,
+    ExportConfigCmd, ImagesCmd, ImportConfigCmd, InspectCmd, ListCmd, LogsCmd, PullCmd, RmiCmd,
+    StartCmd, StopCmd,
 };
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand};
+#[cfg(target_os = "linux")]
+use krunvm::backend::{DEFAULT_BACKEND, ROOTFS_BACKEND};
+use krunvm::context::resolve_config_dir;
+use krunvm::storage;
+use krunvm::KrunvmConfig;
+use krunvm::KrunvmError;
 #[cfg(target_os = "macos")]
 use nix::unistd::execve;
-use serde_derive::{Deserialize, Serialize};
 #[cfg(target_os = "macos")]
 use text_io::read;
 
-#[allow(unused)]
-mod bindings;
 mod commands;
-mod utils;
-
-const APP_NAME: &str = "krunvm";
-
-#[derive(Default, Debug, Serialize, Deserialize)]
-pub struct VmConfig {
-    name: String,
-    cpus: u32,
-    mem: u32,
-    container: String,
-    workdir: String,
-    dns: String,
-    mapped_volumes: HashMap<String, String>,
-    mapped_ports: HashMap<String, String>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct KrunvmConfig {
-    version: u8,
-    default_cpus: u32,
-    default_mem: u32,
-    default_dns: String,
-    storage_volume: String,
-    vmconfig_map: HashMap<String, VmConfig>,
-}
-
-impl Default for KrunvmConfig {
-    fn default() -> KrunvmConfig {
-        KrunvmConfig {
-            version: 1,
-            default_cpus: 2,
-            default_mem: 1024,
-            default_dns: "1.1.1.1".to_string(),
-            storage_volume: String::new(),
-            vmconfig_map: HashMap::new(),
-        }
-    }
-}
 
 #[cfg(target_os = "macos")]
 fn check_case_sensitivity(volume: &str) -> Result<bool, io::Error> {
@@ -131,7 +98,7 @@ volume.
                     println!("success.");
                     println!("The volume has been configured. Please execute krunvm again");
                     cfg.storage_volume = volume;
-                    confy::store(APP_NAME, cfg).unwrap();
+                    storage::store_config(cfg).unwrap();
                     std::process::exit(-1);
                 } else {
                     println!("failed.");
@@ -146,6 +113,39 @@ volume.
     }
 }
 
+/// Returns whether `command` needs the user namespace of a "buildah
+/// unshare" session. That's the case when it manages containers with
+/// buildah, for the default backend and VMs created before backends were
+/// selectable, and when it bind-mounts volumes into the root filesystem of
+/// a VM, whatever its backend.
+#[cfg(target_os = "linux")]
+fn needs_unshare(command: &Command, cfg: &KrunvmConfig) -> bool {
+    let vm_backend = |name: &str| cfg.vmconfig_map.get(name).map(|vm| vm.backend.as_str());
+    let backend = match command {
+        Command::Start(StartCmd { name, volumes, .. }) => {
+            let vm = cfg.vmconfig_map.get(name);
+            if !volumes.is_empty() || vm.is_some_and(|vm| !vm.mapped_volumes.is_empty()) {
+                return true;
+            }
+            vm_backend(name)
+        }
+        Command::Delete(DeleteCmd { name, .. })
+        | Command::Commit(CommitCmd { name, .. })
+        | Command::Inspect(InspectCmd { name, .. }) => vm_backend(name),
+        Command::Create(CreateCmd {
+            rootfs: Some(_), ..
+        }) => Some(ROOTFS_BACKEND),
+        Command::Create(_)
+        | Command::Apply(_)
+        | Command::ImportConfig(_)
+        | Command::Images(_)
+        | Command::Pull(_)
+        | Command::Rmi(_) => Some(cfg.backend.as_str()),
+        _ => None,
+    };
+    matches!(backend, Some("") | Some(DEFAULT_BACKEND))
+}
+
 #[cfg(target_os = "linux")]
 fn check_unshare() {
     let uid = unsafe { libc::getuid() };
@@ -158,9 +158,14 @@ fn check_unshare() {
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Cli {
-    /// Sets the level of verbosity
-    #[arg(short)]
-    verbosity: Option<u8>, //TODO: implement or remove this
+    /// Log buildah invocations, configuration accesses and libkrun calls to stderr; repeat for more detail
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Directory holding the configuration, overriding KRUNVM_CONFIG and the active context
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -168,6 +173,9 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Command {
     Start(StartCmd),
+    Stop(StopCmd),
+    Exec(ExecCmd),
+    Logs(LogsCmd),
     Create(CreateCmd),
     Inspect(InspectCmd),
     List(ListCmd),
@@ -175,6 +183,16 @@ enum Command {
     #[command(name = "changevm")]
     ChangeVm(ChangeVmCmd),
     Config(ConfigCmd),
+    Apply(ApplyCmd),
+    Commit(CommitCmd),
+    #[command(name = "export-config")]
+    ExportConfig(ExportConfigCmd),
+    #[command(name = "import-config")]
+    ImportConfig(ImportConfigCmd),
+    Context(ContextCmd),
+    Images(ImagesCmd),
+    Pull(PullCmd),
+    Rmi(RmiCmd),
 }
 
 #[cfg(target_os = "macos")]
@@ -236,21 +254,127 @@ fn main() {
         }
     }
 
-    let mut cfg: KrunvmConfig = confy::load(APP_NAME).unwrap();
     let cli_args = Cli::parse();
 
+    if let Err(err) = run(cli_args) {
+        eprintln!("{}", err);
+        std::process::exit(exit_code(&err));
+    }
+}
+
+fn exit_code(err: &KrunvmError) -> i32 {
+    match err {
+        KrunvmError::InvalidInput(_) => 2,
+        KrunvmError::VmNotFound(_) => 3,
+        KrunvmError::Config(_) => 4,
+        KrunvmError::Buildah(_) | KrunvmError::ContainerNotFound(_) => 5,
+        KrunvmError::Libkrun { .. } | KrunvmError::VmSetup(_) => 6,
+        KrunvmError::Io(_, _) => 1,
+    }
+}
+
+fn run(cli_args: Cli) -> Result<(), KrunvmError> {
+    krunvm::trace::set_level(cli_args.verbose);
+
+    // Contexts select the configuration to be loaded, so they are
+    // managed before loading any.
+    if let Command::Context(cmd) = cli_args.command {
+        return cmd.run(cli_args.config.is_some());
+    }
+
+    let config_dir = resolve_config_dir(cli_args.config.as_deref()).map_err(KrunvmError::Config)?;
+    let cfg_lock = storage::lock_config(&config_dir).map_err(KrunvmError::Config)?;
+    #[allow(unused_mut)]
+    let (mut cfg, notices) = storage::load_config(&config_dir).map_err(KrunvmError::Config)?;
+    for notice in notices {
+        println!("{}", notice);
+    }
+
     #[cfg(target_os = "macos")]
     check_volume(&mut cfg);
+    // Only buildah and volumes need the user namespace it sets up, the
+    // other backends manage plain directories.
     #[cfg(target_os = "linux")]
-    check_unshare();
+    if needs_unshare(&cli_args.command, &cfg) {
+        check_unshare();
+    }
+
+    match cli_args.command {
+        // Read-only commands only need the configuration to stay as loaded
+        // while they run, which other read-only commands don't prevent.
+        Command::Inspect(_) | Command::List(_) | Command::ExportConfig(_) | Command::Images(_) => {
+            cfg_lock.downgrade().map_err(KrunvmError::Config)?
+        }
+        // Commands that may run for a long time don't keep other instances
+        // from changing the configuration meanwhile. Running, committing
+        // and executing commands in a VM hold its lock instead, keeping it
+        // from being deleted or renamed, while create only locks the
+        // configuration again to register the VM.
+        Command::Start(_)
+        | Command::Stop(_)
+        | Command::Exec(_)
+        | Command::Logs(_)
+        | Command::Create(_)
+        | Command::Pull(_)
+        | Command::Commit(_) => drop(cfg_lock),
+        _ => {}
+    }
 
     match cli_args.command {
-        Command::Inspect(cmd) => cmd.run(&mut cfg),
+        Command::Inspect(cmd) => cmd.run(&cfg),
         Command::Start(cmd) => cmd.run(&cfg),
+        Command::Stop(cmd) => cmd.run(&cfg),
+        Command::Exec(cmd) => cmd.run(&cfg),
+        Command::Logs(cmd) => cmd.run(&cfg),
         Command::Create(cmd) => cmd.run(&mut cfg),
         Command::List(cmd) => cmd.run(&cfg),
         Command::Delete(cmd) => cmd.run(&mut cfg),
         Command::ChangeVm(cmd) => cmd.run(&mut cfg),
         Command::Config(cmd) => cmd.run(&mut cfg),
+        Command::Apply(cmd) => cmd.run(&mut cfg),
+        Command::Commit(cmd) => cmd.run(&cfg),
+        Command::ExportConfig(cmd) => cmd.run(&cfg),
+        Command::ImportConfig(cmd) => cmd.run(&mut cfg),
+        Command::Images(cmd) => cmd.run(&cfg),
+        Command::Pull(cmd) => cmd.run(&cfg),
+        Command::Rmi(cmd) => cmd.run(&cfg),
+        Command::Context(_) => unreachable!(),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use krunvm::VmConfig;
+
+    fn command(args: &[&str]) -> Command {
+        Cli::parse_from([&["krunvm"], args].concat()).command
+    }
+
+    #[test]
+    fn requires_unshare_for_buildah_and_volumes() {
+        let mut cfg = KrunvmConfig::default();
+        for (name, backend) in [("plain", ROOTFS_BACKEND), ("bh", DEFAULT_BACKEND)].iter() {
+            let vmcfg = VmConfig {
+                name: name.to_string(),
+                backend: backend.to_string(),
+                ..Default::default()
+            };
+            cfg.vmconfig_map.insert(name.to_string(), vmcfg);
+        }
+
+        assert!(!needs_unshare(&command(&["start", "plain"]), &cfg));
+        assert!(needs_unshare(&command(&["start", "bh"]), &cfg));
+        assert!(needs_unshare(
+            &command(&["start", "plain", "--volume", "/tmp:/data"]),
+            &cfg
+        ));
+
+        let vmcfg = cfg.vmconfig_map.get_mut("plain").unwrap();
+        vmcfg
+            .mapped_volumes
+            .insert("/tmp".to_string(), "/data".to_string());
+        assert!(needs_unshare(&command(&["start", "plain"]), &cfg));
+        assert!(!needs_unshare(&command(&["list"]), &cfg));
     }
 }
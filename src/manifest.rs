@@ -0,0 +1,499 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde_derive::Deserialize;
+
+use crate::backend::Backends;
+use crate::utils::{
+    path_pairs_to_hash_map, port_pairs_to_hash_map, ulimits_to_hash_map, PathPair, PortPair, Ulimit,
+};
+use crate::vm::{change_vm, create_vm, ChangeOptions, CreateOptions};
+use crate::{KrunvmConfig, KrunvmError, VmConfig};
+
+/// A file describing one or more VMs, as consumed by `krunvm apply`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub vm: Vec<VmManifest>,
+}
+
+/// The desired state of a single VM. Unset values take the global
+/// defaults when the VM is created, and are left untouched when it
+/// already exists.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmManifest {
+    pub name: String,
+    pub image: String,
+    pub cpus: Option<u32>,
+    pub mem: Option<u32>,
+    pub dns: Option<String>,
+    pub workdir: Option<String>,
+    /// Volumes in "host_path:guest_path" format.
+    pub volumes: Option<Vec<String>>,
+    /// Ports in "host_port:guest_port" format.
+    pub ports: Option<Vec<String>>,
+    /// Environment variables, as a table of KEY = "VALUE".
+    pub env: Option<HashMap<String, String>>,
+    /// Command to run when the VM is started without one.
+    pub command: Option<String>,
+    /// Arguments to be passed to `command`, which must be given as well.
+    pub args: Option<Vec<String>>,
+    /// Log level of libkrun, from 0 (off) to 5 (trace).
+    pub krun_log_level: Option<u32>,
+    /// Resource limits in "resource=soft[:hard]" format.
+    pub ulimits: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum ApplyAction {
+    Created,
+    Updated(Vec<String>),
+    Unchanged,
+}
+
+/// The outcome of applying a `VmManifest`.
+#[derive(Debug)]
+pub struct ApplyReport {
+    pub name: String,
+    pub action: ApplyAction,
+    /// Differences that can't be reconciled on an existing VM.
+    pub warnings: Vec<String>,
+}
+
+impl fmt::Display for ApplyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.action {
+            ApplyAction::Created => write!(f, "{}: created", self.name)?,
+            ApplyAction::Updated(changes) => {
+                write!(f, "{}: updated {}", self.name, changes.join(", "))?
+            }
+            ApplyAction::Unchanged => write!(f, "{}: unchanged", self.name)?,
+        }
+        for warning in self.warnings.iter() {
+            write!(f, "\n {}: {}", self.name, warning)?;
+        }
+        Ok(())
+    }
+}
+
+impl Manifest {
+    pub fn from_file(path: &Path) -> Result<Manifest, KrunvmError> {
+        let data = fs::read_to_string(path)
+            .map_err(|err| KrunvmError::Io(format!("reading {}", path.display()), err))?;
+        toml::from_str(&data).map_err(|err| {
+            KrunvmError::InvalidInput(format!("Invalid manifest {}: {}", path.display(), err))
+        })
+    }
+}
+
+fn parse_pairs<T: std::str::FromStr<Err = &'static str>>(
+    name: &str,
+    values: &[String],
+) -> Result<Vec<T>, KrunvmError> {
+    values
+        .iter()
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|err| KrunvmError::InvalidInput(format!("{}: {}: {}", name, value, err)))
+        })
+        .collect()
+}
+
+fn parse_volumes(
+    name: &str,
+    volumes: &Option<Vec<String>>,
+) -> Result<Option<HashMap<String, String>>, KrunvmError> {
+    match volumes {
+        Some(volumes) => Ok(Some(path_pairs_to_hash_map(parse_pairs::<PathPair>(
+            name, volumes,
+        )?))),
+        None => Ok(None),
+    }
+}
+
+fn parse_ports(
+    name: &str,
+    ports: &Option<Vec<String>>,
+) -> Result<Option<HashMap<String, String>>, KrunvmError> {
+    match ports {
+        Some(ports) => Ok(Some(port_pairs_to_hash_map(parse_pairs::<PortPair>(
+            name, ports,
+        )?))),
+        None => Ok(None),
+    }
+}
+
+fn parse_ulimits(
+    name: &str,
+    ulimits: &Option<Vec<String>>,
+) -> Result<Option<HashMap<String, String>>, KrunvmError> {
+    match ulimits {
+        Some(ulimits) => Ok(Some(ulimits_to_hash_map(parse_pairs::<Ulimit>(
+            name, ulimits,
+        )?))),
+        None => Ok(None),
+    }
+}
+
+/// Creates the VMs described in `manifest` that don't exist yet, and
+/// reconciles the ones that do with their description. `on_report` is
+/// called as soon as each VM has been applied, so the VMs applied before
+/// an error are still reported.
+pub fn apply_manifest(
+    cfg: &mut KrunvmConfig,
+    backends: &dyn Backends,
+    manifest: Manifest,
+    on_report: &mut dyn FnMut(ApplyReport),
+) -> Result<(), KrunvmError> {
+    // Validate every entry before touching any VM.
+    let mut entries = Vec::new();
+    let mut names = HashSet::new();
+    for vm in manifest.vm {
+        if !names.insert(vm.name.clone()) {
+            return Err(KrunvmError::InvalidInput(format!(
+                "{}: described more than once",
+                vm.name
+            )));
+        }
+        if vm.args.is_some() && vm.command.is_none() {
+            return Err(KrunvmError::InvalidInput(format!(
+                "{}: args can only be given along with command",
+                vm.name
+            )));
+        }
+        let volumes = parse_volumes(&vm.name, &vm.volumes)?;
+        let ports = parse_ports(&vm.name, &vm.ports)?;
+        let ulimits = parse_ulimits(&vm.name, &vm.ulimits)?;
+        entries.push((vm, volumes, ports, ulimits));
+    }
+
+    for (vm, volumes, ports, ulimits) in entries {
+        let report = match cfg.vmconfig_map.get(&vm.name) {
+            None => {
+                let opts = CreateOptions {
+                    image: vm.image,
+                    rootfs: None,
+                    name: Some(vm.name.clone()),
+                    cpus: vm.cpus,
+                    mem: vm.mem,
+                    dns: vm.dns,
+                    workdir: vm.workdir.unwrap_or_default(),
+                    mapped_volumes: volumes.unwrap_or_default(),
+                    mapped_ports: ports.unwrap_or_default(),
+                    env: vm.env.unwrap_or_default(),
+                    ulimits: ulimits.unwrap_or_default(),
+                    command: vm.command.unwrap_or_default(),
+                    args: vm.args.unwrap_or_default(),
+                    krun_log_level: vm.krun_log_level.unwrap_or_default(),
+                    #[cfg(target_os = "macos")]
+                    x86: false,
+                };
+                let (_, warnings) = create_vm(cfg, backends, opts)?;
+                ApplyReport {
+                    name: vm.name,
+                    action: ApplyAction::Created,
+                    warnings,
+                }
+            }
+            Some(vmcfg) => {
+                let (opts, changes, warnings) = diff_vm(vmcfg, &vm, volumes, ports, ulimits);
+                let action = if changes.is_empty() {
+                    ApplyAction::Unchanged
+                } else {
+                    change_vm(cfg, &vm.name, opts)?;
+                    ApplyAction::Updated(changes)
+                };
+                ApplyReport {
+                    name: vm.name,
+                    action,
+                    warnings,
+                }
+            }
+        };
+        on_report(report);
+    }
+
+    Ok(())
+}
+
+/// Computes the changes needed to bring `vmcfg` to the state described in
+/// `vm`, along with a description of each of them.
+fn diff_vm(
+    vmcfg: &VmConfig,
+    vm: &VmManifest,
+    volumes: Option<HashMap<String, String>>,
+    ports: Option<HashMap<String, String>>,
+    ulimits: Option<HashMap<String, String>>,
+) -> (ChangeOptions, Vec<String>, Vec<String>) {
+    let mut opts = ChangeOptions::default();
+    let mut changes = Vec::new();
+    let mut warnings = Vec::new();
+
+    if !vmcfg.image.is_empty() && vmcfg.image != vm.image {
+        warnings.push(format!(
+            "image is {}, not {}; delete the VM to recreate it from the new image",
+            vmcfg.image, vm.image
+        ));
+    }
+
+    if let Some(dns) = &vm.dns {
+        if *dns != vmcfg.dns {
+            warnings.push(format!(
+                "dns is {}, not {}; delete the VM to recreate it with the new DNS server",
+                vmcfg.dns, dns
+            ));
+        }
+    }
+
+    if let Some(cpus) = vm.cpus {
+        if cpus != vmcfg.cpus {
+            changes.push(format!("cpus {} -> {}", vmcfg.cpus, cpus));
+            opts.cpus = Some(cpus);
+        }
+    }
+
+    if let Some(mem) = vm.mem {
+        if mem != vmcfg.mem {
+            changes.push(format!("mem {} -> {}", vmcfg.mem, mem));
+            opts.mem = Some(mem);
+        }
+    }
+
+    if let Some(workdir) = &vm.workdir {
+        if *workdir != vmcfg.workdir {
+            changes.push(format!("workdir {:?} -> {:?}", vmcfg.workdir, workdir));
+            opts.workdir = Some(workdir.clone());
+        }
+    }
+
+    if let Some(volumes) = volumes {
+        if volumes != vmcfg.mapped_volumes {
+            changes.push(format!(
+                "volumes {:?} -> {:?}",
+                vmcfg.mapped_volumes, volumes
+            ));
+            opts.remove_volumes = volumes.is_empty();
+            opts.mapped_volumes = volumes;
+        }
+    }
+
+    if let Some(ports) = ports {
+        if ports != vmcfg.mapped_ports {
+            changes.push(format!("ports {:?} -> {:?}", vmcfg.mapped_ports, ports));
+            opts.remove_ports = ports.is_empty();
+            opts.mapped_ports = ports;
+        }
+    }
+
+    if let Some(env) = &vm.env {
+        if *env != vmcfg.env {
+            changes.push(format!("env {:?} -> {:?}", vmcfg.env, env));
+            opts.unset_env = vmcfg
+                .env
+                .keys()
+                .filter(|key| !env.contains_key(*key))
+                .cloned()
+                .collect();
+            opts.env = env.clone();
+        }
+    }
+
+    if let Some(ulimits) = ulimits {
+        if ulimits != vmcfg.ulimits {
+            changes.push(format!("ulimits {:?} -> {:?}", vmcfg.ulimits, ulimits));
+            opts.unset_ulimits = vmcfg
+                .ulimits
+                .keys()
+                .filter(|resource| !ulimits.contains_key(*resource))
+                .cloned()
+                .collect();
+            opts.ulimits = ulimits;
+        }
+    }
+
+    if let Some(command) = &vm.command {
+        let args = vm.args.clone().unwrap_or_default();
+        if *command != vmcfg.command || args != vmcfg.args {
+            changes.push(format!(
+                "command {:?} {:?} -> {:?} {:?}",
+                vmcfg.command, vmcfg.args, command, args
+            ));
+            opts.command = Some(command.clone());
+            opts.args = args;
+        }
+    }
+
+    if let Some(level) = vm.krun_log_level {
+        if level != vmcfg.krun_log_level {
+            changes.push(format!(
+                "krun_log_level {} -> {}",
+                vmcfg.krun_log_level, level
+            ));
+            opts.krun_log_level = Some(level);
+        }
+    }
+
+    (opts, changes, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{test_config, FakeBackend, TestDir};
+
+    fn vmcfg() -> VmConfig {
+        VmConfig {
+            name: "vm".to_string(),
+            image: "alpine".to_string(),
+            cpus: 2,
+            mem: 1024,
+            dns: "1.1.1.1".to_string(),
+            env: [("A", "1"), ("B", "2")]
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            mapped_volumes: [("/tmp".to_string(), "/guest".to_string())]
+                .iter()
+                .cloned()
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn manifest(extra: &str) -> VmManifest {
+        toml::from_str(&format!("name = \"vm\"\nimage = \"alpine\"\n{}", extra)).unwrap()
+    }
+
+    fn diff(vm: &VmManifest) -> (ChangeOptions, Vec<String>, Vec<String>) {
+        diff_vm(
+            &vmcfg(),
+            vm,
+            parse_volumes(&vm.name, &vm.volumes).unwrap(),
+            parse_ports(&vm.name, &vm.ports).unwrap(),
+            parse_ulimits(&vm.name, &vm.ulimits).unwrap(),
+        )
+    }
+
+    #[test]
+    fn leaves_unset_and_matching_values_alone() {
+        let (_, changes, warnings) = diff(&manifest("cpus = 2\nvolumes = [\"/tmp:/guest\"]"));
+        assert!(changes.is_empty());
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn changes_differing_values() {
+        let (opts, changes, warnings) = diff(&manifest(
+            "cpus = 4\nmem = 1024\nvolumes = []\n[env]\nA = \"1\"\nC = \"3\"",
+        ));
+        assert_eq!(changes.len(), 3);
+        assert!(warnings.is_empty());
+        assert_eq!(opts.cpus, Some(4));
+        assert_eq!(opts.mem, None);
+        assert!(opts.remove_volumes);
+        assert_eq!(opts.unset_env, vec!["B".to_string()]);
+        assert_eq!(opts.env.len(), 2);
+    }
+
+    #[test]
+    fn warns_about_values_fixed_at_creation() {
+        let vm = VmManifest {
+            image: "fedora".to_string(),
+            ..manifest("dns = \"8.8.8.8\"")
+        };
+        let (_, changes, warnings) = diff(&vm);
+        assert!(changes.is_empty());
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        let vm = manifest("ports = [\"8080\"]");
+        assert!(parse_ports(&vm.name, &vm.ports).is_err());
+        let vm = manifest("ulimits = [\"nofile=2:1\"]");
+        assert!(parse_ulimits(&vm.name, &vm.ulimits).is_err());
+    }
+
+    #[test]
+    fn requires_command_along_with_args() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image("0123", "docker.io/library/alpine:latest");
+        let apply = |cfg: &mut KrunvmConfig, extra: &str| {
+            let manifest = toml::from_str(&format!(
+                "[[vm]]\nname = \"vm\"\nimage = \"alpine\"\n{}",
+                extra
+            ))
+            .unwrap();
+            let mut reports = Vec::new();
+            apply_manifest(cfg, &backend, manifest, &mut |report| reports.push(report))
+                .map(|_| reports)
+        };
+
+        apply(&mut cfg, "command = \"/bin/sh\"\nargs = [\"-c\", \"true\"]").unwrap();
+        assert!(matches!(
+            apply(&mut cfg, "args = [\"-c\", \"false\"]"),
+            Err(KrunvmError::InvalidInput(_))
+        ));
+        assert_eq!(cfg.vmconfig_map["vm"].args, vec!["-c", "true"]);
+
+        let reports = apply(
+            &mut cfg,
+            "command = \"/bin/sh\"\nargs = [\"-c\", \"false\"]",
+        )
+        .unwrap();
+        assert!(matches!(reports[0].action, ApplyAction::Updated(_)));
+        assert_eq!(cfg.vmconfig_map["vm"].args, vec!["-c", "false"]);
+    }
+
+    #[test]
+    fn reports_vms_applied_before_an_error() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image("0123", "docker.io/library/alpine:latest");
+        let manifest = toml::from_str(
+            "[[vm]]\nname = \"first\"\nimage = \"alpine\"\n\
+             [[vm]]\nname = \"second\"\nimage = \"fedora\"",
+        )
+        .unwrap();
+
+        let mut reports = Vec::new();
+        assert!(apply_manifest(&mut cfg, &backend, manifest, &mut |report| {
+            reports.push(report)
+        })
+        .is_err());
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].name, "first");
+        assert!(cfg.vmconfig_map.contains_key("first"));
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image("0123", "docker.io/library/alpine:latest");
+        let manifest = toml::from_str(
+            "[[vm]]\nname = \"vm\"\nimage = \"alpine\"\n\
+             [[vm]]\nname = \"vm\"\nimage = \"alpine\"\ncpus = 4",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            apply_manifest(&mut cfg, &backend, manifest, &mut |_| {}),
+            Err(KrunvmError::InvalidInput(_))
+        ));
+        assert!(cfg.vmconfig_map.is_empty());
+        assert!(backend.state.borrow().containers.is_empty());
+    }
+}
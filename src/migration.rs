@@ -0,0 +1,128 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{Error, ErrorKind};
+
+use toml::Value;
+
+/// Version of the configuration schema written by this build of krunvm.
+/// Bump it and append an entry to `MIGRATIONS` whenever the layout of
+/// `KrunvmConfig` or `VmConfig` changes in a way `#[serde(default)]`
+/// can't cope with.
+pub const CONFIG_VERSION: u8 = 2;
+
+/// A migration upgrades the global configuration table and the list of
+/// VM configuration tables from one version to the next.
+type Migration = fn(&mut Value, &mut Vec<Value>) -> Result<(), Error>;
+
+/// `MIGRATIONS[n]` upgrades a configuration from version `n + 1` to
+/// version `n + 2`.
+const MIGRATIONS: [Migration; (CONFIG_VERSION - 1) as usize] = [migrate_v1_to_v2];
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Returns the schema version of a global configuration table. Files
+/// predating the `version` field are considered version 1.
+pub fn config_version(global: &Value) -> Result<u8, Error> {
+    match global.get("version") {
+        None => Ok(1),
+        Some(Value::Integer(version)) if *version >= 1 && *version <= u8::MAX as i64 => {
+            Ok(*version as u8)
+        }
+        Some(version) => Err(invalid_data(format!(
+            "invalid configuration version: {}",
+            version
+        ))),
+    }
+}
+
+/// Upgrades the configuration in place from `version` to `CONFIG_VERSION`.
+pub fn migrate(version: u8, global: &mut Value, vms: &mut Vec<Value>) -> Result<(), Error> {
+    if version > CONFIG_VERSION {
+        return Err(invalid_data(format!(
+            "the configuration was written by a newer version of krunvm (schema version {}), \
+             while this one only supports up to version {}. Please upgrade krunvm",
+            version, CONFIG_VERSION
+        )));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        migration(global, vms)
+            .map_err(|err| invalid_data(format!("migrating from version {}: {}", from + 1, err)))?;
+    }
+
+    if let Some(table) = global.as_table_mut() {
+        table.insert("version".to_string(), Value::Integer(CONFIG_VERSION as i64));
+    }
+
+    Ok(())
+}
+
+/// Version 1 kept every VM under the `vmconfig_map` table of the global
+/// configuration. Version 2 keeps each of them in its own file.
+fn migrate_v1_to_v2(global: &mut Value, vms: &mut Vec<Value>) -> Result<(), Error> {
+    let table = global
+        .as_table_mut()
+        .ok_or_else(|| invalid_data("the global configuration is not a table".to_string()))?;
+
+    if let Some(map) = table.remove("vmconfig_map") {
+        let map = match map {
+            Value::Table(map) => map,
+            _ => return Err(invalid_data("vmconfig_map is not a table".to_string())),
+        };
+        vms.extend(map.into_iter().map(|(_, vmcfg)| vmcfg));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &str) -> Value {
+        toml::from_str(data).unwrap()
+    }
+
+    #[test]
+    fn moves_vms_out_of_the_global_configuration() {
+        let mut global = parse(
+            r#"
+            default_cpus = 2
+            [vmconfig_map.first]
+            name = "first"
+            [vmconfig_map.second]
+            name = "second"
+            "#,
+        );
+        let mut vms = Vec::new();
+
+        migrate_v1_to_v2(&mut global, &mut vms).unwrap();
+        assert!(global.get("vmconfig_map").is_none());
+        assert_eq!(global["default_cpus"].as_integer(), Some(2));
+        let mut names: Vec<&str> = vms.iter().map(|vm| vm["name"].as_str().unwrap()).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn migrates_configurations_without_vms() {
+        let mut global = parse("default_cpus = 2");
+        let mut vms = Vec::new();
+
+        migrate(config_version(&global).unwrap(), &mut global, &mut vms).unwrap();
+        assert!(vms.is_empty());
+        assert_eq!(global["version"].as_integer(), Some(CONFIG_VERSION as i64));
+    }
+
+    #[test]
+    fn rejects_invalid_configurations() {
+        let mut vms = Vec::new();
+        assert!(migrate_v1_to_v2(&mut parse("vmconfig_map = 1"), &mut vms).is_err());
+        assert!(migrate_v1_to_v2(&mut Value::Integer(1), &mut vms).is_err());
+        assert!(config_version(&parse("version = 0")).is_err());
+        assert!(migrate(CONFIG_VERSION + 1, &mut parse(""), &mut vms).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use directories::ProjectDirs;
+use toml::Value;
+
+use crate::migration::{config_version, migrate, CONFIG_VERSION};
+use crate::trace;
+use crate::{KrunvmConfig, VmConfig, VmState, APP_NAME};
+
+const VMS_DIR: &str = "vms";
+const RUN_DIR: &str = "run";
+const LOGS_DIR: &str = "logs";
+const STATE_FILE: &str = "state.toml";
+const LOCK_FILE: &str = "krunvm.lock";
+const VM_LOCK_FILE: &str = "lock";
+/// Directory under the runtime directory holding the VMs of
+/// configurations other than the default one. VM names can't start with
+/// a dot, so it can't clash with them.
+const RUNTIME_CONTEXTS_DIR: &str = ".contexts";
+
+/// Advisory lock serializing every read-modify-write cycle on the
+/// configuration. The lock is released when this value is dropped or
+/// the process exits.
+pub struct ConfigLock {
+    file: File,
+}
+
+/// Advisory lock held for as long as a VM runs, or is being modified in
+/// ways that require it not to. Forked processes inherit it, so it stays
+/// held until the last of them exits.
+pub struct VmLock {
+    _file: File,
+}
+
+/// Returns the directory holding the configuration when no other one has
+/// been selected.
+pub fn default_config_dir() -> Result<PathBuf, Error> {
+    let project = ProjectDirs::from("rs", "", APP_NAME).ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            "couldn't determine the configuration directory",
+        )
+    })?;
+    Ok(project.config_dir().to_path_buf())
+}
+
+/// Returns the directory where krunvm keeps the data it manages itself,
+/// such as the root filesystems unpacked by the oci backend.
+pub fn default_data_dir() -> Result<PathBuf, Error> {
+    let project = ProjectDirs::from("rs", "", APP_NAME)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "couldn't determine the data directory"))?;
+    Ok(project.data_local_dir().to_path_buf())
+}
+
+fn global_config_path(dir: &Path) -> PathBuf {
+    dir.join(format!("{}.toml", APP_NAME))
+}
+
+fn vm_config_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(VMS_DIR).join(format!("{}.toml", name))
+}
+
+/// 64-bit FNV-1a hash, used to derive stable directory names from paths.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Returns the directory holding the runtime files of the VMs configured
+/// in `dir`: `$XDG_RUNTIME_DIR/krunvm` for the default configuration, and
+/// a subdirectory of it named after a hash of `dir` for any other. Without
+/// a runtime directory, as on macOS, they are kept in `dir` itself.
+pub fn runtime_dir(dir: &Path) -> PathBuf {
+    let base = match ProjectDirs::from("rs", "", APP_NAME)
+        .as_ref()
+        .and_then(ProjectDirs::runtime_dir)
+    {
+        Some(base) => base.to_path_buf(),
+        None => return dir.join(RUN_DIR),
+    };
+
+    let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = canonical(dir);
+    match default_config_dir() {
+        Ok(default) if canonical(&default) == dir => base,
+        _ => base
+            .join(RUNTIME_CONTEXTS_DIR)
+            .join(format!("{:016x}", fnv1a(dir.as_os_str().as_bytes()))),
+    }
+}
+
+/// Returns the directory holding the runtime files of the VM named `name`.
+pub fn vm_run_dir(dir: &Path, name: &str) -> PathBuf {
+    runtime_dir(dir).join(name)
+}
+
+/// Returns the file the console of the VM named `name` is written to when
+/// it runs detached.
+pub fn vm_log_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(LOGS_DIR).join(format!("{}.log", name))
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("toml"))
+}
+
+/// Returns whether `name` can be safely used as the file name of a VM
+/// configuration.
+pub fn is_valid_vm_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/')
+}
+
+pub fn lock_config(dir: &Path) -> Result<ConfigLock, Error> {
+    fs::create_dir_all(dir)?;
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(ConfigLock { file })
+}
+
+impl ConfigLock {
+    /// Lets other instances take the lock too, as long as they don't
+    /// modify the configuration either. Meant for read-only commands, once
+    /// the configuration has been loaded, which may have upgraded it.
+    pub fn downgrade(&self) -> Result<(), Error> {
+        let ret = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_SH) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Takes the lock of the VM named `name` without waiting for it, returning
+/// `None` if another process holds it.
+pub fn try_lock_vm(dir: &Path, name: &str) -> Result<Option<VmLock>, Error> {
+    let run_dir = vm_run_dir(dir, name);
+    fs::create_dir_all(&run_dir)?;
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(run_dir.join(VM_LOCK_FILE))?;
+
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret < 0 {
+        let err = Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+
+    Ok(Some(VmLock { _file: file }))
+}
+
+fn read_toml<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let data = fs::read_to_string(path)?;
+    toml::from_str(&data).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    })
+}
+
+/// Writes `data` to `path` through a temporary file in the same directory,
+/// so readers either see the previous contents or the new ones, but never
+/// a partially written file.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let dir = path.parent().unwrap();
+    let tmp_path = dir.join(format!(
+        ".{}.tmp",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    File::open(dir)?.sync_all()
+}
+
+fn write_toml<T: serde::Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    let data = toml::to_string(value).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    write_atomic(path, data.as_bytes())
+}
+
+fn read_value(path: &Path) -> Result<Value, Error> {
+    read_toml(path)
+}
+
+fn into_config<T: serde::de::DeserializeOwned>(value: Value) -> Result<T, Error> {
+    value
+        .try_into()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Copies the current configuration files into a `backup-vN` directory
+/// before they get rewritten by a migration, returning its path.
+fn backup_config(dir: &Path, version: u8, vm_paths: &[PathBuf]) -> Result<PathBuf, Error> {
+    let backup_dir = dir.join(format!("backup-v{}", version));
+    fs::create_dir_all(backup_dir.join(VMS_DIR))?;
+
+    let global_path = global_config_path(dir);
+    if global_path.exists() {
+        fs::copy(
+            &global_path,
+            backup_dir.join(global_path.file_name().unwrap()),
+        )?;
+    }
+    for path in vm_paths {
+        fs::copy(
+            path,
+            backup_dir.join(VMS_DIR).join(path.file_name().unwrap()),
+        )?;
+    }
+
+    Ok(backup_dir)
+}
+
+/// Loads the global configuration and every VM configuration, upgrading
+/// them first if they were written by an older version of krunvm, along
+/// with notices about any such upgrade. Callers intending to modify and
+/// store the configuration must hold the `ConfigLock` for the whole cycle.
+pub fn load_config(dir: &Path) -> Result<(KrunvmConfig, Vec<String>), Error> {
+    let global_path = global_config_path(dir);
+
+    let mut global = match read_value(&global_path) {
+        Ok(global) => global,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            Value::try_from(KrunvmConfig::default())
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut vm_paths = Vec::new();
+    match fs::read_dir(dir.join(VMS_DIR)) {
+        Ok(entries) => {
+            for entry in entries {
+                let path = entry?.path();
+                if is_toml(&path) {
+                    vm_paths.push(path);
+                }
+            }
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let mut vms = vm_paths
+        .iter()
+        .map(|path| read_value(path))
+        .collect::<Result<Vec<Value>, Error>>()?;
+
+    let mut notices = Vec::new();
+    let version = config_version(&global)?;
+    if version != CONFIG_VERSION {
+        migrate(version, &mut global, &mut vms)?;
+        let backup_dir = backup_config(dir, version, &vm_paths)?;
+        notices.push(format!(
+            "Upgrading the krunvm configuration from version {} to version {}. \
+             A copy of the original files has been saved in {}",
+            version,
+            CONFIG_VERSION,
+            backup_dir.display()
+        ));
+    }
+
+    let mut cfg: KrunvmConfig = into_config(global)?;
+    cfg.config_dir = dir.to_path_buf();
+    for vm in vms {
+        let vmcfg: VmConfig = into_config(vm)?;
+        cfg.vmconfig_map.insert(vmcfg.name.clone(), vmcfg);
+    }
+
+    trace::event(
+        trace::DEBUG,
+        "config-load",
+        &[
+            ("dir", &dir.display()),
+            ("version", &version),
+            ("vms", &cfg.vmconfig_map.len()),
+        ],
+    );
+
+    if version != CONFIG_VERSION {
+        store_config(&cfg)?;
+    }
+
+    Ok((cfg, notices))
+}
+
+/// Stores the global configuration and every VM configuration, removing
+/// the files of VMs that are no longer present in `cfg`.
+pub fn store_config(cfg: &KrunvmConfig) -> Result<(), Error> {
+    if cfg.config_dir.as_os_str().is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the configuration wasn't loaded from any directory",
+        ));
+    }
+    let dir = cfg.config_dir.as_path();
+    let vms_dir = dir.join(VMS_DIR);
+    fs::create_dir_all(&vms_dir)?;
+
+    let mut current = HashSet::new();
+    for vmcfg in cfg.vmconfig_map.values() {
+        if !is_valid_vm_name(&vmcfg.name) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid VM name: {}", vmcfg.name),
+            ));
+        }
+        let path = vm_config_path(dir, &vmcfg.name);
+        write_toml(&path, vmcfg)?;
+        current.insert(path);
+    }
+
+    for entry in fs::read_dir(&vms_dir)? {
+        let path = entry?.path();
+        if is_toml(&path) && !current.contains(&path) {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    // The global file goes last, so a configuration being migrated only
+    // gets its new version once all its VMs have been safely written.
+    write_toml(&global_config_path(dir), cfg)?;
+
+    trace::event(
+        trace::DEBUG,
+        "config-store",
+        &[("dir", &dir.display()), ("vms", &cfg.vmconfig_map.len())],
+    );
+    Ok(())
+}
+
+/// Loads the runtime state of the VM named `name`, if it was ever started.
+pub fn load_vm_state(dir: &Path, name: &str) -> Result<Option<VmState>, Error> {
+    match read_toml(&vm_run_dir(dir, name).join(STATE_FILE)) {
+        Ok(state) => Ok(Some(state)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn store_vm_state(dir: &Path, name: &str, state: &VmState) -> Result<(), Error> {
+    let run_dir = vm_run_dir(dir, name);
+    fs::create_dir_all(&run_dir)?;
+    write_toml(&run_dir.join(STATE_FILE), state)
+}
@@ -0,0 +1,46 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Diagnostics enabled with `krunvm -v`, printed to stderr one event per
+//! line, as `krunvm: EVENT key=value...`.
+
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Every buildah invocation, configuration load and store, and libkrun
+/// call, along with its outcome.
+pub const DEBUG: u8 = 1;
+/// Also the output of buildah and the settings passed to libkrun.
+pub const TRACE: u8 = 2;
+
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_level(level: u8) {
+    LEVEL.store(level, Ordering::Relaxed);
+}
+
+pub fn enabled(level: u8) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level
+}
+
+/// Prints `event` along with `fields` if the verbosity is at least
+/// `level`. Values that are empty or contain spaces, quotes or `=` are
+/// quoted, so each line can be split unambiguously.
+pub fn event(level: u8, event: &str, fields: &[(&str, &dyn Display)]) {
+    if !enabled(level) {
+        return;
+    }
+
+    let mut line = format!("krunvm: {}", event);
+    for (key, value) in fields {
+        let value = value.to_string();
+        if value.is_empty()
+            || value.contains(|c: char| c.is_whitespace() || c.is_control() || c == '"' || c == '=')
+        {
+            let _ = write!(line, " {}={:?}", key, value);
+        } else {
+            let _ = write!(line, " {}={}", key, value);
+        }
+    }
+    eprintln!("{}", line);
+}
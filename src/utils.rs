@@ -3,74 +3,9 @@
 
 use std::collections::HashMap;
 use std::path::Path;
-use std::process::Command;
 use std::str::FromStr;
 
-use crate::{KrunvmConfig, VmConfig, APP_NAME};
-
-pub enum BuildahCommand {
-    From,
-    Inspect,
-    Mount,
-    Unmount,
-    Remove,
-}
-
-#[cfg(target_os = "linux")]
-pub fn get_buildah_args(_cfg: &KrunvmConfig, cmd: BuildahCommand) -> Vec<String> {
-    match cmd {
-        BuildahCommand::From => vec!["from".to_string()],
-        BuildahCommand::Inspect => vec!["inspect".to_string()],
-        BuildahCommand::Mount => vec!["mount".to_string()],
-        BuildahCommand::Unmount => vec!["umount".to_string()],
-        BuildahCommand::Remove => vec!["rm".to_string()],
-    }
-}
-
-#[cfg(target_os = "macos")]
-pub fn get_buildah_args(cfg: &KrunvmConfig, cmd: BuildahCommand) -> Vec<String> {
-    let mut hbpath = std::env::current_exe().unwrap();
-    hbpath.pop();
-    hbpath.pop();
-    let hbpath = hbpath.as_path().display();
-    let policy_json = format!("{}/etc/containers/policy.json", hbpath);
-    let registries_json = format!("{}/etc/containers/registries.conf", hbpath);
-    let storage_root = format!("{}/root", cfg.storage_volume);
-    let storage_runroot = format!("{}/runroot", cfg.storage_volume);
-
-    let mut args = vec![
-        "--root".to_string(),
-        storage_root,
-        "--runroot".to_string(),
-        storage_runroot,
-    ];
-
-    match cmd {
-        BuildahCommand::From => {
-            args.push("--signature-policy".to_string());
-            args.push(policy_json);
-            args.push("--registries-conf".to_string());
-            args.push(registries_json);
-
-            args.push("from".to_string());
-            args.push("--os".to_string());
-            args.push("linux".to_string());
-        }
-        BuildahCommand::Inspect => {
-            args.push("inspect".to_string());
-        }
-        BuildahCommand::Mount => {
-            args.push("mount".to_string());
-        }
-        BuildahCommand::Unmount => {
-            args.push("umount".to_string());
-        }
-        BuildahCommand::Remove => {
-            args.push("rm".to_string());
-        }
-    }
-    args
-}
+use crate::KrunvmError;
 
 #[derive(Debug, Clone)]
 pub struct PortPair {
@@ -161,134 +96,201 @@ impl FromStr for PathPair {
     }
 }
 
-#[cfg(target_os = "macos")]
-fn fix_root_mode(rootfs: &str) {
-    let mut args = vec!["-w", "user.containers.override_stat", "0:0:0555"];
-    args.push(rootfs);
-
-    let output = match Command::new("xattr")
-        .args(&args)
-        .stderr(std::process::Stdio::inherit())
-        .output()
-    {
-        Ok(output) => output,
-        Err(err) => {
-            if err.kind() == std::io::ErrorKind::NotFound {
-                println!("{} requires xattr to manage the OCI images, and it wasn't found on this system.", APP_NAME);
-            } else {
-                println!("Error executing xattr: {}", err);
-            }
-            std::process::exit(-1);
-        }
-    };
+#[derive(Debug, Clone)]
+pub struct EnvPair {
+    pub key: String,
+    pub value: String,
+}
 
-    let exit_code = output.status.code().unwrap_or(-1);
-    if exit_code != 0 {
-        println!("xattr returned an error: {}", exit_code);
-        std::process::exit(-1);
-    }
+pub fn env_pairs_to_hash_map(
+    env_pairs: impl IntoIterator<Item = EnvPair>,
+) -> HashMap<String, String> {
+    env_pairs
+        .into_iter()
+        .map(|pair: EnvPair| (pair.key, pair.value))
+        .collect()
 }
 
-#[allow(unused_variables)]
-pub fn mount_container(cfg: &KrunvmConfig, vmcfg: &VmConfig) -> Result<String, std::io::Error> {
-    let mut args = get_buildah_args(cfg, BuildahCommand::Mount);
-    args.push(vmcfg.container.clone());
-
-    let output = match Command::new("buildah")
-        .args(&args)
-        .stderr(std::process::Stdio::inherit())
-        .output()
-    {
-        Ok(output) => output,
-        Err(err) => {
-            if err.kind() == std::io::ErrorKind::NotFound {
-                println!("{} requires buildah to manage the OCI images, and it wasn't found on this system.", APP_NAME);
-            } else {
-                println!("Error executing buildah: {}", err);
-            }
-            std::process::exit(-1);
-        }
-    };
+impl FromStr for EnvPair {
+    type Err = &'static str;
 
-    let exit_code = output.status.code().unwrap_or(-1);
-    if exit_code != 0 {
-        println!(
-            "buildah returned an error: {}",
-            std::str::from_utf8(&output.stdout).unwrap()
-        );
-        std::process::exit(-1);
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (key, value) = match input.split_once('=') {
+            Some(pair) => pair,
+            None => return Err("Missing '=' separator"),
+        };
+        if key.is_empty() {
+            return Err("Invalid env, key is empty");
+        }
+        if key.contains('\0') || value.contains('\0') {
+            return Err("Invalid env, contains a NUL byte");
+        }
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
     }
+}
 
-    let rootfs = std::str::from_utf8(&output.stdout).unwrap().trim();
+/// Resources whose limits can be set in the guest, along with their
+/// identifiers on Linux, which libkrun expects whatever the host is.
+pub const ULIMIT_RESOURCES: [(&str, u32); 16] = [
+    ("cpu", 0),
+    ("fsize", 1),
+    ("data", 2),
+    ("stack", 3),
+    ("core", 4),
+    ("rss", 5),
+    ("nproc", 6),
+    ("nofile", 7),
+    ("memlock", 8),
+    ("as", 9),
+    ("locks", 10),
+    ("sigpending", 11),
+    ("msgqueue", 12),
+    ("nice", 13),
+    ("rtprio", 14),
+    ("rttime", 15),
+];
+
+/// Value of a limit with no upper bound, RLIM_INFINITY on Linux.
+pub const UNLIMITED: u64 = u64::MAX;
+
+/// A resource limit in format "resource=soft[:hard]", where the hard
+/// limit defaults to the soft one and either can be "unlimited".
+#[derive(Debug, Clone)]
+pub struct Ulimit {
+    pub resource: String,
+    pub soft: u64,
+    pub hard: u64,
+}
 
-    #[cfg(target_os = "macos")]
-    fix_root_mode(rootfs);
+impl Ulimit {
+    /// Identifier of the resource on Linux.
+    pub fn resource_id(&self) -> u32 {
+        ULIMIT_RESOURCES
+            .iter()
+            .find(|(name, _)| *name == self.resource)
+            .map(|(_, id)| *id)
+            .unwrap()
+    }
 
-    Ok(rootfs.to_string())
+    /// The limits in format "soft:hard", as stored in the VM
+    /// configuration.
+    pub fn limits(&self) -> String {
+        format!("{}:{}", format_limit(self.soft), format_limit(self.hard))
+    }
 }
 
-#[allow(unused_variables)]
-pub fn umount_container(cfg: &KrunvmConfig, vmcfg: &VmConfig) -> Result<(), std::io::Error> {
-    let mut args = get_buildah_args(cfg, BuildahCommand::Unmount);
-    args.push(vmcfg.container.clone());
-
-    let output = match Command::new("buildah")
-        .args(&args)
-        .stderr(std::process::Stdio::inherit())
-        .output()
-    {
-        Ok(output) => output,
-        Err(err) => {
-            if err.kind() == std::io::ErrorKind::NotFound {
-                println!("{} requires buildah to manage the OCI images, and it wasn't found on this system.", APP_NAME);
-            } else {
-                println!("Error executing buildah: {}", err);
-            }
-            std::process::exit(-1);
-        }
-    };
+pub fn format_limit(limit: u64) -> String {
+    if limit == UNLIMITED {
+        "unlimited".to_string()
+    } else {
+        limit.to_string()
+    }
+}
 
-    let exit_code = output.status.code().unwrap_or(-1);
-    if exit_code != 0 {
-        println!(
-            "buildah returned an error: {}",
-            std::str::from_utf8(&output.stdout).unwrap()
-        );
-        std::process::exit(-1);
+fn parse_limit(input: &str) -> Result<u64, &'static str> {
+    match input {
+        "unlimited" => Ok(UNLIMITED),
+        _ => input
+            .parse()
+            .map_err(|_| "Invalid ulimit, limits must be numbers or \"unlimited\""),
     }
+}
 
-    Ok(())
+pub fn ulimits_to_hash_map(ulimits: impl IntoIterator<Item = Ulimit>) -> HashMap<String, String> {
+    ulimits
+        .into_iter()
+        .map(|ulimit: Ulimit| (ulimit.resource.clone(), ulimit.limits()))
+        .collect()
 }
 
-#[allow(unused_variables)]
-pub fn remove_container(cfg: &KrunvmConfig, vmcfg: &VmConfig) -> Result<(), std::io::Error> {
-    let mut args = get_buildah_args(cfg, BuildahCommand::Remove);
-    args.push(vmcfg.container.clone());
-
-    let output = match Command::new("buildah")
-        .args(&args)
-        .stderr(std::process::Stdio::inherit())
-        .output()
-    {
-        Ok(output) => output,
-        Err(err) => {
-            if err.kind() == std::io::ErrorKind::NotFound {
-                println!("{} requires buildah to manage the OCI images, and it wasn't found on this system.", APP_NAME);
-            } else {
-                println!("Error executing buildah: {}", err);
+impl FromStr for Ulimit {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (resource, limits) = match input.split_once('=') {
+            Some(pair) => pair,
+            None => return Err("Missing '=' separator"),
+        };
+        if !ULIMIT_RESOURCES.iter().any(|(name, _)| *name == resource) {
+            return Err(
+                "Invalid ulimit, unknown resource. Supported resources are cpu, fsize, \
+                        data, stack, core, rss, nproc, nofile, memlock, as, locks, sigpending, \
+                        msgqueue, nice, rtprio and rttime",
+            );
+        }
+        let (soft, hard) = match limits.split_once(':') {
+            Some((soft, hard)) => (parse_limit(soft)?, parse_limit(hard)?),
+            None => {
+                let limit = parse_limit(limits)?;
+                (limit, limit)
             }
-            std::process::exit(-1);
+        };
+        if soft > hard {
+            return Err("Invalid ulimit, the soft limit is greater than the hard limit");
         }
-    };
+        Ok(Self {
+            resource: resource.to_string(),
+            soft,
+            hard,
+        })
+    }
+}
+
+/// Reads "KEY=VALUE" pairs from `path`, one per line, skipping empty lines
+/// and those starting with '#'.
+pub fn read_env_file(path: &Path) -> Result<Vec<EnvPair>, KrunvmError> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|err| KrunvmError::Io(format!("reading {}", path.display()), err))?;
+
+    data.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse().map_err(|err| {
+                KrunvmError::InvalidInput(format!("{}: {}: {}", path.display(), line, err))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let exit_code = output.status.code().unwrap_or(-1);
-    if exit_code != 0 {
-        println!(
-            "buildah returned an error: {}",
-            std::str::from_utf8(&output.stdout).unwrap()
+    #[test]
+    fn parses_ulimits() {
+        let ulimit: Ulimit = "nofile=1024:4096".parse().unwrap();
+        assert_eq!(
+            (ulimit.resource.as_str(), ulimit.soft, ulimit.hard),
+            ("nofile", 1024, 4096)
         );
-        std::process::exit(-1);
+        assert_eq!(ulimit.resource_id(), 7);
+
+        let ulimit: Ulimit = "core=unlimited".parse().unwrap();
+        assert_eq!((ulimit.soft, ulimit.hard), (UNLIMITED, UNLIMITED));
+        assert_eq!(ulimit.limits(), "unlimited:unlimited");
+
+        let ulimit: Ulimit = "stack=8192:unlimited".parse().unwrap();
+        assert_eq!(ulimit.limits(), "8192:unlimited");
     }
 
-    Ok(())
+    #[test]
+    fn rejects_invalid_ulimits() {
+        for value in [
+            "nofile",
+            "files=1024",
+            "nofile=",
+            "nofile=-1",
+            "nofile=1024:",
+            "nofile=4096:1024",
+            "nofile=unlimited:1024",
+        ]
+        .iter()
+        {
+            assert!(value.parse::<Ulimit>().is_err(), "{} was accepted", value);
+        }
+    }
 }
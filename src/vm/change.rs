@@ -0,0 +1,196 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+
+use super::get_vm;
+use super::logs::log_files;
+use super::start::lock_vm;
+use super::{
+    validate_cpus, validate_krun_log_level, validate_mem, validate_new_name, validate_ulimits,
+};
+use crate::storage::{store_config, vm_run_dir};
+use crate::{KrunvmConfig, KrunvmError, VmConfig};
+
+/// Changes to apply to an existing VM. Unset values are left untouched.
+#[derive(Debug, Default)]
+pub struct ChangeOptions {
+    pub new_name: Option<String>,
+    pub cpus: Option<u32>,
+    pub mem: Option<u32>,
+    pub workdir: Option<String>,
+    pub remove_volumes: bool,
+    pub mapped_volumes: HashMap<String, String>,
+    pub remove_ports: bool,
+    pub mapped_ports: HashMap<String, String>,
+    /// Environment variables to add or replace.
+    pub env: HashMap<String, String>,
+    /// Environment variables to remove.
+    pub unset_env: Vec<String>,
+    /// Resource limits to add or replace, as produced by
+    /// `ulimits_to_hash_map`.
+    pub ulimits: HashMap<String, String>,
+    /// Resource limits to remove.
+    pub unset_ulimits: Vec<String>,
+    /// Command, along with `args`, to run when the VM is started without
+    /// one. An empty string means the image's default.
+    pub command: Option<String>,
+    pub args: Vec<String>,
+    pub krun_log_level: Option<u32>,
+}
+
+/// Moves the state and logs of a VM that has been renamed from `old_name`
+/// to `new_name`.
+fn rename_runtime_files(
+    cfg: &KrunvmConfig,
+    old_name: &str,
+    new_name: &str,
+) -> Result<(), KrunvmError> {
+    let new_run_dir = vm_run_dir(&cfg.config_dir, new_name);
+    // No VM was using the new name, so anything there is stale.
+    let mut renames = vec![fs::remove_dir_all(&new_run_dir)];
+    renames.push(fs::rename(
+        vm_run_dir(&cfg.config_dir, old_name),
+        &new_run_dir,
+    ));
+    for (old_log, new_log) in log_files(cfg, old_name)
+        .iter()
+        .zip(log_files(cfg, new_name).iter())
+    {
+        renames.push(fs::rename(old_log, new_log));
+    }
+
+    for ret in renames {
+        match ret {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(KrunvmError::Io("moving runtime files".to_string(), err))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Applies `opts` to the VM named `name`, storing the configuration if
+/// anything changed, and returns the resulting VM configuration.
+pub fn change_vm<'a>(
+    cfg: &'a mut KrunvmConfig,
+    name: &str,
+    opts: ChangeOptions,
+) -> Result<&'a VmConfig, KrunvmError> {
+    let mut cfg_changed = false;
+
+    if let Some(cpus) = opts.cpus {
+        validate_cpus(cpus)?;
+    }
+    if let Some(mem) = opts.mem {
+        validate_mem(mem)?;
+    }
+    if let Some(level) = opts.krun_log_level {
+        validate_krun_log_level(level)?;
+    }
+    validate_ulimits(&opts.ulimits)?;
+
+    // The runtime files of the VM are named after it, so it can't be
+    // renamed while it runs.
+    let mut renamed = None;
+    let name = if let Some(new_name) = opts.new_name {
+        validate_new_name(cfg, &new_name)?;
+        get_vm(cfg, name)?;
+        let lock = lock_vm(cfg, name)?;
+        renamed = Some((name.to_string(), lock));
+
+        let mut vmcfg = cfg
+            .vmconfig_map
+            .remove(name)
+            .ok_or_else(|| KrunvmError::VmNotFound(name.to_string()))?;
+
+        cfg_changed = true;
+        vmcfg.name = new_name.clone();
+        cfg.vmconfig_map.insert(new_name.clone(), vmcfg);
+        new_name
+    } else {
+        name.to_string()
+    };
+
+    let vmcfg = cfg
+        .vmconfig_map
+        .get_mut(&name)
+        .ok_or_else(|| KrunvmError::VmNotFound(name.to_string()))?;
+
+    if let Some(cpus) = opts.cpus {
+        vmcfg.cpus = cpus;
+        cfg_changed = true;
+    }
+
+    if let Some(mem) = opts.mem {
+        vmcfg.mem = mem;
+        cfg_changed = true;
+    }
+
+    if opts.remove_volumes {
+        vmcfg.mapped_volumes = HashMap::new();
+        cfg_changed = true;
+    } else if !opts.mapped_volumes.is_empty() {
+        vmcfg.mapped_volumes = opts.mapped_volumes;
+        cfg_changed = true;
+    }
+    // TODO: don't just silently ignore --volume args when --remove_volumes is specified
+
+    if opts.remove_ports {
+        vmcfg.mapped_ports = HashMap::new();
+        cfg_changed = true;
+    } else if !opts.mapped_ports.is_empty() {
+        vmcfg.mapped_ports = opts.mapped_ports;
+        cfg_changed = true;
+    }
+    // TODO: don't just silently ignore --port args when --remove_ports is specified
+
+    for key in opts.unset_env.iter() {
+        if vmcfg.env.remove(key).is_some() {
+            cfg_changed = true;
+        }
+    }
+    if !opts.env.is_empty() {
+        vmcfg.env.extend(opts.env);
+        cfg_changed = true;
+    }
+
+    for resource in opts.unset_ulimits.iter() {
+        if vmcfg.ulimits.remove(resource).is_some() {
+            cfg_changed = true;
+        }
+    }
+    if !opts.ulimits.is_empty() {
+        vmcfg.ulimits.extend(opts.ulimits);
+        cfg_changed = true;
+    }
+
+    if let Some(command) = opts.command {
+        vmcfg.command = command;
+        vmcfg.args = opts.args;
+        cfg_changed = true;
+    }
+
+    if let Some(workdir) = opts.workdir {
+        vmcfg.workdir = workdir;
+        cfg_changed = true;
+    }
+
+    if let Some(level) = opts.krun_log_level {
+        vmcfg.krun_log_level = level;
+        cfg_changed = true;
+    }
+
+    if cfg_changed {
+        store_config(cfg).map_err(KrunvmError::Config)?;
+    }
+
+    if let Some((old_name, _lock)) = renamed {
+        rename_runtime_files(cfg, &old_name, &name)?;
+    }
+
+    Ok(&cfg.vmconfig_map[&name])
+}
@@ -0,0 +1,237 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs;
+use std::io::ErrorKind;
+
+use super::create::resolv_conf;
+use super::get_vm;
+use super::start::{lock_vm, LEGACY_LOCK_FILE};
+use crate::backend::{Backends, CommitConfig, ContainerBackend};
+use crate::{KrunvmConfig, KrunvmError, VmConfig};
+
+/// Files left behind by krunvm inside the root filesystem, which don't
+/// belong in a committed image.
+const ARTIFACTS: [&str; 3] = [LEGACY_LOCK_FILE, ".krunvm-mount.sh", ".krunvm-agent"];
+/// Configuration libkrun reads when starting the VM, set aside while
+/// committing it.
+const KRUN_CONFIG_FILE: &str = ".krun_config.json";
+/// Mount point of Rosetta on macOS, set aside while committing the VM.
+#[cfg(target_os = "macos")]
+const ROSETTA_DIR: &str = ".rosetta";
+
+/// Builds the image configuration to be committed from the settings
+/// stored for the VM.
+fn commit_config(vmcfg: &VmConfig) -> CommitConfig {
+    let mut env: Vec<String> = vmcfg
+        .env
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    env.sort();
+
+    let mut ports: Vec<String> = vmcfg
+        .mapped_ports
+        .values()
+        .map(|port| format!("{}/tcp", port))
+        .collect();
+    ports.sort();
+    ports.dedup();
+
+    CommitConfig {
+        env,
+        workdir: Some(vmcfg.workdir.clone()).filter(|workdir| !workdir.is_empty()),
+        command: if vmcfg.command.is_empty() {
+            None
+        } else {
+            Some(
+                std::iter::once(vmcfg.command.clone())
+                    .chain(vmcfg.args.iter().cloned())
+                    .collect(),
+            )
+        },
+        ports,
+    }
+}
+
+fn remove_file(path: &str) -> Result<(), KrunvmError> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(KrunvmError::Io(format!("removing {}", path), err))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Root filesystem of a VM mounted to commit it. The files krunvm needs
+/// to run the VM are set aside while committing, and put back by
+/// `restore`. It's unmounted when dropped, whether committing succeeded
+/// or not.
+struct MountedRootfs<'a> {
+    backend: &'a dyn ContainerBackend,
+    container: &'a str,
+    path: String,
+    /// Paths set aside, along with the contents of files, or `None` for
+    /// directories.
+    stashed: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl<'a> MountedRootfs<'a> {
+    fn mount(
+        backend: &'a dyn ContainerBackend,
+        container: &'a str,
+    ) -> Result<MountedRootfs<'a>, KrunvmError> {
+        Ok(MountedRootfs {
+            path: backend.mount(container)?,
+            backend,
+            container,
+            stashed: Vec::new(),
+        })
+    }
+
+    fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name)
+    }
+
+    /// Sets aside the file `name`, if it exists.
+    fn stash_file(&mut self, name: &str) -> Result<(), KrunvmError> {
+        let path = self.path(name);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(KrunvmError::Io(format!("reading {}", path), err)),
+        };
+        remove_file(&path)?;
+        self.stashed.push((path, Some(contents)));
+        Ok(())
+    }
+
+    /// Sets aside the directory `name`, if it exists and is empty.
+    #[cfg(target_os = "macos")]
+    fn stash_dir(&mut self, name: &str) -> Result<(), KrunvmError> {
+        let path = self.path(name);
+        if fs::remove_dir(&path).is_ok() {
+            self.stashed.push((path, None));
+        }
+        Ok(())
+    }
+
+    /// Puts back everything set aside.
+    fn restore(&mut self) -> Result<(), KrunvmError> {
+        let mut ret = Ok(());
+        for (path, contents) in self.stashed.drain(..) {
+            let restored = match contents {
+                Some(contents) => fs::write(&path, contents),
+                None => fs::create_dir(&path),
+            };
+            if let Err(err) = restored {
+                ret = Err(KrunvmError::Io(format!("restoring {}", path), err));
+            }
+        }
+        ret
+    }
+}
+
+impl Drop for MountedRootfs<'_> {
+    fn drop(&mut self) {
+        let _ = self.restore();
+        let _ = self.backend.umount(self.container);
+    }
+}
+
+/// Saves the current root filesystem of the VM named `name` as `image`,
+/// leaving out the files krunvm added to it. The VM must not be running.
+pub fn commit_vm(
+    cfg: &KrunvmConfig,
+    backends: &dyn Backends,
+    name: &str,
+    image: &str,
+) -> Result<(), KrunvmError> {
+    let vmcfg = get_vm(cfg, name)?;
+    let backend = backends.vm_backend(cfg, vmcfg)?;
+
+    // Holding the lock keeps the VM from being started meanwhile.
+    let _lock = lock_vm(cfg, name)?;
+    let mut rootfs = MountedRootfs::mount(backend.as_ref(), &vmcfg.container)?;
+
+    for artifact in ARTIFACTS {
+        remove_file(&rootfs.path(artifact))?;
+    }
+    rootfs.stash_file(KRUN_CONFIG_FILE)?;
+    #[cfg(target_os = "macos")]
+    rootfs.stash_dir(ROSETTA_DIR)?;
+    // Only drop resolv.conf if it's still the one krunvm wrote.
+    let injected = resolv_conf(&vmcfg.dns);
+    let is_injected = fs::read_to_string(rootfs.path("etc/resolv.conf"))
+        .map(|contents| contents == injected)
+        .unwrap_or(false);
+    if is_injected {
+        rootfs.stash_file("etc/resolv.conf")?;
+    }
+
+    let ret = backend.commit(&vmcfg.container, image, &commit_config(vmcfg));
+    let restored = rootfs.restore();
+    ret.and(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{test_config, FakeBackend, TestDir};
+    use crate::vm::{create_vm, CreateOptions};
+
+    fn setup(dir: &TestDir) -> (KrunvmConfig, FakeBackend) {
+        let mut cfg = test_config(dir);
+        let backend = FakeBackend::new();
+        backend.add_image("0123", "docker.io/library/alpine:latest");
+        let opts = CreateOptions {
+            image: "alpine".to_string(),
+            name: Some("vm".to_string()),
+            ..Default::default()
+        };
+        create_vm(&mut cfg, &backend, opts).unwrap();
+        (cfg, backend)
+    }
+
+    #[test]
+    fn commits_without_krunvm_files() {
+        let dir = TestDir::new();
+        let (cfg, backend) = setup(&dir);
+        let rootfs = backend.rootfs(&cfg.vmconfig_map["vm"].container);
+        fs::write(rootfs.join(".krunvm-agent"), "").unwrap();
+        fs::write(rootfs.join("data"), "").unwrap();
+
+        commit_vm(&cfg, &backend, "vm", "image").unwrap();
+        let state = backend.state.borrow();
+        assert_eq!(
+            state.commits,
+            vec![(
+                "image".to_string(),
+                vec!["data".to_string(), "etc".to_string()]
+            )]
+        );
+        assert!(!state.containers.values().any(|container| container.mounted));
+        // What the VM needs to run is put back.
+        assert!(rootfs.join(KRUN_CONFIG_FILE).is_file());
+        assert!(rootfs.join("etc/resolv.conf").is_file());
+        assert!(!rootfs.join(".krunvm-agent").exists());
+    }
+
+    #[test]
+    fn restores_files_when_committing_fails() {
+        let dir = TestDir::new();
+        let (cfg, backend) = setup(&dir);
+        let rootfs = backend.rootfs(&cfg.vmconfig_map["vm"].container);
+        backend.state.borrow_mut().failing = Some("commit");
+
+        assert!(commit_vm(&cfg, &backend, "vm", "image").is_err());
+        assert!(rootfs.join(KRUN_CONFIG_FILE).is_file());
+        assert!(rootfs.join("etc/resolv.conf").is_file());
+        assert!(!backend
+            .state
+            .borrow()
+            .containers
+            .values()
+            .any(|container| container.mounted));
+    }
+}
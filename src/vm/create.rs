@@ -0,0 +1,404 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+#[cfg(target_os = "macos")]
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::{
+    validate_cpus, validate_krun_log_level, validate_mem, validate_new_name, validate_ulimits,
+};
+use crate::backend::{Backends, ContainerBackend, ROOTFS_BACKEND};
+use crate::image::InspectInfo;
+use crate::storage::store_config;
+use crate::{KrunvmConfig, KrunvmError, VmConfig};
+
+#[cfg(target_os = "macos")]
+const KRUNVM_ROSETTA_FILE: &str = ".krunvm-rosetta";
+
+/// Settings for a new VM. Unset values are taken from the global
+/// configuration.
+#[derive(Debug, Default)]
+pub struct CreateOptions {
+    /// Image to create the VM from. Ignored if `rootfs` is set.
+    pub image: String,
+    /// Existing directory to be used as the root filesystem of the VM,
+    /// instead of creating a container from `image`.
+    pub rootfs: Option<PathBuf>,
+    pub name: Option<String>,
+    pub cpus: Option<u32>,
+    pub mem: Option<u32>,
+    pub dns: Option<String>,
+    pub workdir: String,
+    pub mapped_volumes: HashMap<String, String>,
+    pub mapped_ports: HashMap<String, String>,
+    pub env: HashMap<String, String>,
+    /// Resource limits of the guest, as produced by `ulimits_to_hash_map`.
+    pub ulimits: HashMap<String, String>,
+    /// Command to run when the VM is started without one. An empty string
+    /// means the image's default.
+    pub command: String,
+    pub args: Vec<String>,
+    pub krun_log_level: u32,
+    #[cfg(target_os = "macos")]
+    pub x86: bool,
+}
+
+/// A VM whose container is ready, but which isn't registered in the
+/// configuration yet.
+#[derive(Debug)]
+pub struct NewVm {
+    vmcfg: VmConfig,
+    warnings: Vec<String>,
+}
+
+impl NewVm {
+    /// Settings of the VM that had to be adjusted.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+/// Creates a new VM from an OCI image and registers it in the
+/// configuration, returning its name along with the settings that had to
+/// be adjusted.
+pub fn create_vm(
+    cfg: &mut KrunvmConfig,
+    backends: &dyn Backends,
+    opts: CreateOptions,
+) -> Result<(String, Vec<String>), KrunvmError> {
+    let vm = prepare_vm(cfg, backends, opts)?;
+    let warnings = vm.warnings.clone();
+    Ok((register_vm(cfg, backends, vm)?, warnings))
+}
+
+/// Creates and sets up the container of a new VM, to be registered with
+/// `register_vm`. Doesn't modify the configuration, so it doesn't need to
+/// be locked meanwhile.
+pub fn prepare_vm(
+    cfg: &KrunvmConfig,
+    backends: &dyn Backends,
+    opts: CreateOptions,
+) -> Result<NewVm, KrunvmError> {
+    #[allow(unused_mut)]
+    let mut cpus = validate_cpus(opts.cpus.unwrap_or(cfg.default_cpus))?;
+    let mem = validate_mem(opts.mem.unwrap_or(cfg.default_mem))?;
+    let dns = opts.dns.unwrap_or_else(|| cfg.default_dns.clone());
+    let krun_log_level = validate_krun_log_level(opts.krun_log_level)?;
+    validate_ulimits(&opts.ulimits)?;
+
+    let (backend_name, image) = match opts.rootfs {
+        Some(rootfs) => (
+            ROOTFS_BACKEND.to_string(),
+            rootfs.to_string_lossy().into_owned(),
+        ),
+        None => (cfg.backend.clone(), opts.image),
+    };
+    // Without a name, VMs are named after their container, which is only
+    // known once created, unless it's a root filesystem directory.
+    let name = match opts.name {
+        None if backend_name == ROOTFS_BACKEND => Some(rootfs_name(&image)),
+        name => name,
+    };
+    if let Some(ref name) = name {
+        validate_new_name(cfg, name)?;
+    }
+    let backend = backends.get(cfg, &backend_name)?;
+    #[allow(unused_mut)]
+    let mut arch = None;
+    #[allow(unused_mut)]
+    let mut warnings = Vec::new();
+
+    #[cfg(target_os = "macos")]
+    let force_x86 = opts.x86;
+
+    #[cfg(target_os = "macos")]
+    if force_x86 {
+        let home = std::env::var("HOME").map_err(|e| {
+            KrunvmError::InvalidInput(format!("Error reading \"HOME\" enviroment variable: {}", e))
+        })?;
+
+        let path = format!("{}/{}", home, KRUNVM_ROSETTA_FILE);
+        if !Path::new(&path).is_file() {
+            return Err(KrunvmError::InvalidInput(format!(
+                "
+To use Rosetta for Linux you need to create the file...
+
+{}
+
+...with the contents that the \"rosetta\" binary expects to be served from
+its specific ioctl.
+
+For more information, please refer to this post:
+https://threedots.ovh/blog/2022/06/quick-look-at-rosetta-on-linux/
+",
+                path
+            )));
+        }
+
+        if cpus != 1 {
+            warnings.push("x86 microVMs on Aarch64 are restricted to 1 CPU".to_string());
+            cpus = 1;
+        }
+        arch = Some("x86_64");
+    }
+
+    let CreateOptions {
+        workdir,
+        mapped_volumes,
+        mapped_ports,
+        env,
+        ulimits,
+        command,
+        args,
+        ..
+    } = opts;
+    let container = backend.from(&image, arch)?;
+    let ret = (|| {
+        let name = match name {
+            Some(name) => name,
+            None => {
+                validate_new_name(cfg, &container)?;
+                container.clone()
+            }
+        };
+        let mut vmcfg = VmConfig {
+            name,
+            image: if backend_name == ROOTFS_BACKEND {
+                String::new()
+            } else {
+                image.clone()
+            },
+            image_id: String::new(),
+            cpus,
+            mem,
+            dns: dns.to_string(),
+            container: container.clone(),
+            backend: backend_name,
+            workdir,
+            mapped_volumes,
+            mapped_ports,
+            env,
+            ulimits,
+            command,
+            args,
+            krun_log_level,
+        };
+
+        let rootfs = backend.mount(&container)?;
+        let info = export_container_config(backend.as_ref(), &rootfs, &image)?;
+        if vmcfg.backend != ROOTFS_BACKEND {
+            vmcfg.image_id = info.from_image_id;
+        }
+        fix_resolv_conf(&rootfs, &dns)
+            .map_err(|err| KrunvmError::Io("writing resolv.conf".to_string(), err))?;
+        #[cfg(target_os = "macos")]
+        if force_x86 {
+            _ = fs::create_dir(format!("{}/.rosetta", rootfs));
+        }
+        backend.umount(&container)?;
+        Ok(NewVm { vmcfg, warnings })
+    })();
+
+    // Nothing refers to the container yet.
+    if ret.is_err() {
+        let _ = backend.umount(&container);
+        let _ = backend.rm(&container);
+    }
+    ret
+}
+
+/// Registers `vm` in the configuration, returning its name. Its container
+/// is removed if it can't be, such as when another VM took its name since
+/// it was prepared.
+pub fn register_vm(
+    cfg: &mut KrunvmConfig,
+    backends: &dyn Backends,
+    vm: NewVm,
+) -> Result<String, KrunvmError> {
+    let name = vm.vmcfg.name.clone();
+    let ret = validate_new_name(cfg, &name).and_then(|_| {
+        cfg.vmconfig_map.insert(name.clone(), vm.vmcfg.clone());
+        store_config(cfg).map_err(|err| {
+            cfg.vmconfig_map.remove(&name);
+            KrunvmError::Config(err)
+        })
+    });
+
+    if ret.is_err() {
+        if let Ok(backend) = backends.vm_backend(cfg, &vm.vmcfg) {
+            let _ = backend.rm(&vm.vmcfg.container);
+        }
+    }
+    ret.map(|_| name)
+}
+
+/// Returns the name of a VM created from the root filesystem directory
+/// `path`, which is the one of the directory.
+fn rootfs_name(path: &str) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Returns the contents of the resolv.conf krunvm writes into the root
+/// filesystem of new VMs.
+pub(super) fn resolv_conf(dns: &str) -> String {
+    format!("options use-vc\nnameserver {}\n", dns)
+}
+
+fn fix_resolv_conf(rootfs: &str, dns: &str) -> Result<(), std::io::Error> {
+    let resolvconf_dir = format!("{}/etc/", rootfs);
+    fs::create_dir_all(resolvconf_dir)?;
+    let resolvconf = format!("{}/etc/resolv.conf", rootfs);
+    let mut file = fs::File::create(resolvconf)?;
+    file.write_all(resolv_conf(dns).as_bytes())?;
+    Ok(())
+}
+
+/// Writes the configuration of `image` into the root filesystem, for
+/// libkrun to pick the command and environment from, returning it.
+fn export_container_config(
+    backend: &dyn ContainerBackend,
+    rootfs: &str,
+    image: &str,
+) -> Result<InspectInfo, KrunvmError> {
+    let config = backend.inspect(image)?;
+
+    let info = InspectInfo::parse(&config)?;
+    if !info.image.os.is_empty() && info.image.os != "linux" {
+        return Err(KrunvmError::InvalidInput(format!(
+            "Image {} is built for {}, only linux images are supported",
+            image, info.image.os
+        )));
+    }
+
+    let path = format!("{}/.krun_config.json", rootfs);
+    let mut file = fs::File::create(&path)
+        .map_err(|err| KrunvmError::Io(format!("creating {}", path), err))?;
+    file.write_all(config.as_bytes())
+        .map_err(|err| KrunvmError::Io(format!("writing {}", path), err))?;
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{test_config, FakeBackend, TestDir};
+
+    const IMAGE_ID: &str = "0123456789abcdef";
+
+    fn create(cfg: &mut KrunvmConfig, backend: &FakeBackend) -> Result<String, KrunvmError> {
+        let opts = CreateOptions {
+            image: "alpine".to_string(),
+            name: Some("vm".to_string()),
+            ..Default::default()
+        };
+        create_vm(cfg, backend, opts).map(|(name, _)| name)
+    }
+
+    #[test]
+    fn creates_vm_from_image() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image(IMAGE_ID, "docker.io/library/alpine:latest");
+
+        assert_eq!(create(&mut cfg, &backend).unwrap(), "vm");
+
+        let vmcfg = &cfg.vmconfig_map["vm"];
+        assert_eq!(vmcfg.image, "alpine");
+        assert_eq!(vmcfg.image_id, IMAGE_ID);
+        assert_eq!(vmcfg.cpus, cfg.default_cpus);
+
+        let rootfs = backend.rootfs(&vmcfg.container);
+        assert!(rootfs.join(".krun_config.json").is_file());
+        let written = fs::read_to_string(rootfs.join("etc/resolv.conf")).unwrap();
+        assert_eq!(written, resolv_conf(&cfg.default_dns));
+        assert!(!backend.state.borrow().containers[&vmcfg.container].mounted);
+
+        let stored = crate::storage::load_config(&cfg.config_dir).unwrap().0;
+        assert_eq!(stored.vmconfig_map["vm"].image_id, IMAGE_ID);
+    }
+
+    #[test]
+    fn removes_container_on_failure() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image(IMAGE_ID, "docker.io/library/alpine:latest");
+        backend.state.borrow_mut().images[0].os = "windows".to_string();
+
+        assert!(create(&mut cfg, &backend).is_err());
+        assert!(backend.state.borrow().containers.is_empty());
+        assert!(cfg.vmconfig_map.is_empty());
+
+        backend.state.borrow_mut().images[0].os = "linux".to_string();
+        backend.state.borrow_mut().failing = Some("mount");
+        assert!(create(&mut cfg, &backend).is_err());
+        assert!(backend.state.borrow().containers.is_empty());
+    }
+
+    #[test]
+    fn names_vm_after_container() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image(IMAGE_ID, "docker.io/library/alpine:latest");
+        let opts = CreateOptions {
+            image: "alpine".to_string(),
+            ..Default::default()
+        };
+
+        let (name, _) = create_vm(&mut cfg, &backend, opts).unwrap();
+        assert_eq!(cfg.vmconfig_map[&name].container, name);
+    }
+
+    #[test]
+    fn registers_prepared_vm_unless_name_was_taken() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image(IMAGE_ID, "docker.io/library/alpine:latest");
+        let opts = || CreateOptions {
+            image: "alpine".to_string(),
+            name: Some("vm".to_string()),
+            ..Default::default()
+        };
+
+        let first = prepare_vm(&cfg, &backend, opts()).unwrap();
+        let second = prepare_vm(&cfg, &backend, opts()).unwrap();
+        assert!(cfg.vmconfig_map.is_empty());
+        assert_eq!(backend.state.borrow().containers.len(), 2);
+
+        assert_eq!(register_vm(&mut cfg, &backend, first).unwrap(), "vm");
+        assert!(register_vm(&mut cfg, &backend, second).is_err());
+        let state = backend.state.borrow();
+        assert_eq!(state.containers.len(), 1);
+        assert!(state
+            .containers
+            .contains_key(&cfg.vmconfig_map["vm"].container));
+    }
+
+    #[test]
+    fn refuses_existing_name() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image(IMAGE_ID, "docker.io/library/alpine:latest");
+
+        create(&mut cfg, &backend).unwrap();
+        backend.state.borrow_mut().failing = Some("from");
+        // The name is checked before creating any container.
+        assert!(matches!(
+            create(&mut cfg, &backend),
+            Err(KrunvmError::InvalidInput(message)) if message.contains("already exists")
+        ));
+        assert_eq!(backend.state.borrow().containers.len(), 1);
+    }
+}
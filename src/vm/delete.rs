@@ -0,0 +1,115 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs;
+use std::io::ErrorKind;
+
+use super::get_vm;
+use super::logs::log_files;
+use super::start::lock_vm;
+use crate::backend::{Backends, ROOTFS_BACKEND};
+use crate::storage::{store_config, vm_run_dir};
+use crate::{KrunvmConfig, KrunvmError};
+
+/// Removes the VM's container and unregisters it from the configuration,
+/// returning warnings about anything that was already gone. The root
+/// filesystem directory of VMs created with `--rootfs` is left untouched
+/// unless `remove_rootfs` is set.
+pub fn delete_vm(
+    cfg: &mut KrunvmConfig,
+    backends: &dyn Backends,
+    name: &str,
+    remove_rootfs: bool,
+) -> Result<Vec<String>, KrunvmError> {
+    get_vm(cfg, name)?;
+    // Kept until the runtime files are gone, so the VM can't be started
+    // meanwhile.
+    let _lock = lock_vm(cfg, name)?;
+
+    let vmcfg = cfg
+        .vmconfig_map
+        .remove(name)
+        .ok_or_else(|| KrunvmError::VmNotFound(name.to_string()))?;
+
+    let backend = backends.vm_backend(cfg, &vmcfg)?;
+    // A container removed behind our back shouldn't keep the VM from
+    // being deleted.
+    let ret = backend
+        .umount(&vmcfg.container)
+        .and_then(|_| backend.rm(&vmcfg.container));
+    let mut warnings = Vec::new();
+    match ret {
+        Err(KrunvmError::ContainerNotFound(container)) => {
+            warnings.push(format!("Container {} was already removed", container))
+        }
+        ret => ret?,
+    }
+
+    if remove_rootfs && vmcfg.backend == ROOTFS_BACKEND {
+        fs::remove_dir_all(&vmcfg.container)
+            .map_err(|err| KrunvmError::Io(format!("removing {}", vmcfg.container), err))?;
+    }
+
+    let mut removals: Vec<_> = log_files(cfg, name).iter().map(fs::remove_file).collect();
+    removals.push(fs::remove_dir_all(vm_run_dir(&cfg.config_dir, name)));
+    for ret in removals {
+        match ret {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(KrunvmError::Io("removing runtime files".to_string(), err))
+            }
+            _ => {}
+        }
+    }
+
+    store_config(cfg).map_err(KrunvmError::Config)?;
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{test_config, FakeBackend, TestDir};
+    use crate::vm::{create_vm, CreateOptions};
+
+    #[test]
+    fn removes_container_and_vm() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image("0123456789abcdef", "docker.io/library/alpine:latest");
+        let opts = CreateOptions {
+            image: "alpine".to_string(),
+            name: Some("vm".to_string()),
+            ..Default::default()
+        };
+        create_vm(&mut cfg, &backend, opts).unwrap();
+
+        assert!(delete_vm(&mut cfg, &backend, "vm", false)
+            .unwrap()
+            .is_empty());
+
+        assert!(cfg.vmconfig_map.is_empty());
+        assert!(backend.state.borrow().containers.is_empty());
+        let stored = crate::storage::load_config(&cfg.config_dir).unwrap().0;
+        assert!(stored.vmconfig_map.is_empty());
+    }
+
+    #[test]
+    fn tolerates_removed_container() {
+        let dir = TestDir::new();
+        let mut cfg = test_config(&dir);
+        let backend = FakeBackend::new();
+        backend.add_image("0123456789abcdef", "docker.io/library/alpine:latest");
+        let opts = CreateOptions {
+            image: "alpine".to_string(),
+            name: Some("vm".to_string()),
+            ..Default::default()
+        };
+        create_vm(&mut cfg, &backend, opts).unwrap();
+        backend.state.borrow_mut().containers.clear();
+
+        let warnings = delete_vm(&mut cfg, &backend, "vm", false).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(cfg.vmconfig_map.is_empty());
+    }
+}
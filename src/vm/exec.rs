@@ -0,0 +1,95 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::convert::TryInto;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use super::get_vm;
+use super::start::AGENT_SOCKET;
+use super::state::vm_state;
+use crate::agent::{read_frame, write_frame, ExecRequest, EXIT, REQUEST, STDERR, STDIN, STDOUT};
+use crate::storage::vm_run_dir;
+use crate::{KrunvmConfig, KrunvmError, VmStatus};
+
+fn agent_error(err: io::Error) -> KrunvmError {
+    KrunvmError::Io("talking to the guest agent".to_string(), err)
+}
+
+/// Forwards the standard input of this process to the command, closing
+/// its standard input once ours reaches EOF.
+fn forward_stdin(mut conn: UnixStream) {
+    let mut stdin = io::stdin();
+    let mut buf = [0u8; 8192];
+    loop {
+        match stdin.read(&mut buf) {
+            Ok(0) | Err(_) => {
+                let _ = write_frame(&mut conn, STDIN, &[]);
+                break;
+            }
+            Ok(len) => {
+                if write_frame(&mut conn, STDIN, &buf[..len]).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Runs `request` in the running VM named `name` through its guest agent,
+/// forwarding the standard streams of this process. Returns the exit code
+/// of the command.
+pub fn exec_in_vm(
+    cfg: &KrunvmConfig,
+    name: &str,
+    request: &ExecRequest,
+) -> Result<i32, KrunvmError> {
+    get_vm(cfg, name)?;
+    if vm_state(cfg, name)?.status != VmStatus::Running {
+        return Err(KrunvmError::InvalidInput(format!(
+            "microVM {} is not running",
+            name
+        )));
+    }
+
+    let socket = vm_run_dir(&cfg.config_dir, name).join(AGENT_SOCKET);
+    if !socket.exists() {
+        return Err(KrunvmError::InvalidInput(format!(
+            "microVM {} was started without the guest agent (krunvm-agent)",
+            name
+        )));
+    }
+    let mut conn = UnixStream::connect(&socket)
+        .map_err(|err| KrunvmError::Io(format!("connecting to {}", socket.display()), err))?;
+
+    let payload = serde_json::to_vec(request).unwrap();
+    write_frame(&mut conn, REQUEST, &payload).map_err(agent_error)?;
+
+    let stdin_conn = conn.try_clone().map_err(agent_error)?;
+    thread::spawn(move || forward_stdin(stdin_conn));
+
+    loop {
+        match read_frame(&mut conn).map_err(agent_error)? {
+            Some((STDOUT, data)) => {
+                let mut stdout = io::stdout();
+                stdout.write_all(&data).map_err(agent_error)?;
+                stdout.flush().map_err(agent_error)?;
+            }
+            Some((STDERR, data)) => io::stderr().write_all(&data).map_err(agent_error)?,
+            Some((EXIT, data)) => {
+                let code: [u8; 4] = data.as_slice().try_into().map_err(|_| {
+                    agent_error(io::Error::new(ErrorKind::InvalidData, "invalid exit code"))
+                })?;
+                return Ok(i32::from_be_bytes(code));
+            }
+            Some(_) => {}
+            None => {
+                return Err(agent_error(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed before the command exited",
+                )))
+            }
+        }
+    }
+}
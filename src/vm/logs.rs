@@ -0,0 +1,539 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::get_vm;
+use super::state::vm_state;
+use crate::storage::{vm_log_path, vm_run_dir};
+use crate::{KrunvmConfig, KrunvmError, VmStatus};
+
+/// Size past which the log of a VM is rotated.
+const MAX_LOG_SIZE: u64 = 4 << 20;
+/// Number of rotated logs kept, as NAME.log.1 (the newest) to NAME.log.N.
+const ROTATED_LOGS: usize = 2;
+/// FIFO libkrun writes the console of a detached VM to.
+const CONSOLE_FIFO: &str = "console";
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Converts days since the Unix epoch into a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Converts a (year, month, day) date into days since the Unix epoch.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Formats `secs` since the Unix epoch as an RFC 3339 UTC timestamp.
+fn format_timestamp(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Parses an RFC 3339 UTC timestamp, as written by `format_timestamp`, or a
+/// plain date.
+fn parse_timestamp(value: &str) -> Option<u64> {
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, time.strip_suffix('Z')?),
+        None => (value, "00:00:00"),
+    };
+
+    let date: Vec<&str> = date.split('-').collect();
+    let time: Vec<&str> = time.split(':').collect();
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+    let year: i64 = date[0].parse().ok()?;
+    let month: u32 = date[1].parse().ok().filter(|m| (1..=12).contains(m))?;
+    let day: u32 = date[2].parse().ok().filter(|d| (1..=31).contains(d))?;
+    let hours: u64 = time[0].parse().ok().filter(|h| *h < 24)?;
+    let mins: u64 = time[1].parse().ok().filter(|m| *m < 60)?;
+    let secs: u64 = time[2].parse().ok().filter(|s| *s < 60)?;
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(days * 86400 + hours * 3600 + mins * 60 + secs)
+}
+
+/// Parses the argument of `logs --since`: either a duration relative to
+/// now, such as "10m" or "1h30m", or an RFC 3339 UTC timestamp or date.
+/// Returns the time in seconds since the Unix epoch.
+pub fn parse_since(value: &str) -> Result<u64, KrunvmError> {
+    if let Some(time) = parse_timestamp(value) {
+        return Ok(time);
+    }
+
+    let invalid = || {
+        KrunvmError::InvalidInput(format!(
+            "Invalid time {}, expected a duration such as 10m or 1h30m, or a \
+             timestamp such as 2021-01-31T12:00:00Z",
+            value
+        ))
+    };
+    let mut secs = 0;
+    let mut digits = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(invalid()),
+        };
+        let amount: u64 = digits.parse().map_err(|_| invalid())?;
+        secs += amount * unit;
+        digits.clear();
+    }
+    if value.is_empty() || !digits.is_empty() {
+        return Err(invalid());
+    }
+    Ok(now().saturating_sub(secs))
+}
+
+fn rotated_log_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", index));
+    PathBuf::from(path)
+}
+
+/// Returns the log files of the VM named `name`, from the oldest to the
+/// current one, whether they exist or not.
+pub(super) fn log_files(cfg: &KrunvmConfig, name: &str) -> Vec<PathBuf> {
+    let path = vm_log_path(&cfg.config_dir, name);
+    let mut files: Vec<PathBuf> = (1..=ROTATED_LOGS)
+        .rev()
+        .map(|index| rotated_log_path(&path, index))
+        .collect();
+    files.push(path);
+    files
+}
+
+/// Log file rotated once it grows past `MAX_LOG_SIZE`. Every line is
+/// prefixed with the time it was written at.
+struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// Whether the last line written is still incomplete.
+    partial: bool,
+}
+
+impl RotatingLog {
+    fn open(path: PathBuf) -> io::Result<RotatingLog> {
+        fs::create_dir_all(path.parent().unwrap())?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingLog {
+            path,
+            file,
+            size,
+            partial: false,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..ROTATED_LOGS).rev() {
+            let _ = fs::rename(
+                rotated_log_path(&self.path, index),
+                rotated_log_path(&self.path, index + 1),
+            );
+        }
+        fs::rename(&self.path, rotated_log_path(&self.path, 1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        for line in data.split_inclusive(|c| *c == b'\n') {
+            if !self.partial {
+                if self.size >= MAX_LOG_SIZE {
+                    self.rotate()?;
+                }
+                let timestamp = format!("{} ", format_timestamp(now()));
+                self.file.write_all(timestamp.as_bytes())?;
+                self.size += timestamp.len() as u64;
+            }
+            self.file.write_all(line)?;
+            self.size += line.len() as u64;
+            self.partial = !line.ends_with(b"\n");
+        }
+        Ok(())
+    }
+}
+
+/// Captures the console of a VM into its log. libkrun writes the console
+/// to a FIFO, either directly or through the standard output of the VM
+/// process, which is copied to the log by a thread of the supervisor
+/// process.
+pub(super) struct ConsoleCapture {
+    fifo: PathBuf,
+    /// Whether the console is also copied to our standard output, for VMs
+    /// running in the foreground.
+    echo: bool,
+    /// Taken by the thread copying the console once started.
+    reader: Option<File>,
+    /// Keeps the reader from seeing EOF before the VM opens the FIFO.
+    writer: Option<File>,
+    log: Arc<Mutex<RotatingLog>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ConsoleCapture {
+    pub(super) fn new(
+        cfg: &KrunvmConfig,
+        name: &str,
+        echo: bool,
+    ) -> Result<ConsoleCapture, KrunvmError> {
+        let log_path = vm_log_path(&cfg.config_dir, name);
+        let log = RotatingLog::open(log_path.clone())
+            .map_err(|err| KrunvmError::Io(format!("opening {}", log_path.display()), err))?;
+
+        let run_dir = vm_run_dir(&cfg.config_dir, name);
+        let fifo = run_dir.join(CONSOLE_FIFO);
+        let open_fifo = || -> io::Result<(File, File)> {
+            fs::create_dir_all(&run_dir)?;
+            match fs::remove_file(&fifo) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+            let c_fifo = CString::new(fifo.as_os_str().as_bytes())?;
+            if unsafe { libc::mkfifo(c_fifo.as_ptr(), 0o600) } < 0 {
+                return Err(Error::last_os_error());
+            }
+            // Opening the reading end blocks until there's a writer,
+            // unless done in non-blocking mode.
+            let reader = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&fifo)?;
+            let writer = OpenOptions::new().write(true).open(&fifo)?;
+            unsafe { libc::fcntl(reader.as_raw_fd(), libc::F_SETFL, 0) };
+            Ok((reader, writer))
+        };
+        let (reader, writer) = open_fifo()
+            .map_err(|err| KrunvmError::Io(format!("creating {}", fifo.display()), err))?;
+
+        Ok(ConsoleCapture {
+            fifo,
+            echo,
+            reader: Some(reader),
+            writer: Some(writer),
+            log: Arc::new(Mutex::new(log)),
+            thread: None,
+        })
+    }
+
+    /// Path libkrun must write the console to, if any. When echoing, it
+    /// keeps writing to the standard output of the VM process, so the
+    /// guest keeps reading the terminal too.
+    pub(super) fn output(&self) -> Option<&Path> {
+        if self.echo {
+            None
+        } else {
+            Some(&self.fifo)
+        }
+    }
+
+    /// File descriptor processes writing to the log can use as their
+    /// standard output and error.
+    pub(super) fn writer_fd(&self) -> RawFd {
+        self.writer.as_ref().map_or(-1, |writer| writer.as_raw_fd())
+    }
+
+    /// Starts copying the console to the log. Must be called from the
+    /// process supervising the VM once it has forked the VM process.
+    pub(super) fn start(&mut self) {
+        let mut reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => return,
+        };
+        let log = self.log.clone();
+        let echo = self.echo;
+        self.thread = Some(thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        if echo {
+                            let mut stdout = io::stdout();
+                            let _ = stdout.write_all(&buf[..len]).and_then(|_| stdout.flush());
+                        }
+                        if log.lock().unwrap().write(&buf[..len]).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        }));
+    }
+
+    /// Writes a message from krunvm itself to the log.
+    pub(super) fn log_message(&self, message: &str) {
+        let _ = self
+            .log
+            .lock()
+            .unwrap()
+            .write(format!("krunvm: {}\n", message).as_bytes());
+    }
+
+    /// Waits for every process writing to the console to exit, and copies
+    /// what's left of it to the log.
+    pub(super) fn drain(&mut self) {
+        self.writer = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    pub(super) fn finish(mut self) {
+        self.drain();
+        let _ = fs::remove_file(&self.fifo);
+    }
+}
+
+/// Settings for `read_logs`.
+#[derive(Debug, Default)]
+pub struct LogOptions {
+    /// Keep printing new lines until the VM stops.
+    pub follow: bool,
+    /// Only print the last lines, this many of them.
+    pub tail: Option<usize>,
+    /// Only print lines written since this time, in seconds since the Unix
+    /// epoch.
+    pub since: Option<u64>,
+    /// Print the time each line was written at.
+    pub timestamps: bool,
+}
+
+/// Splits a log line into its timestamp and contents.
+fn split_line(line: &str) -> (Option<u64>, &str) {
+    match line.split_once(' ') {
+        Some((timestamp, contents)) => match parse_timestamp(timestamp) {
+            Some(time) => (Some(time), contents),
+            None => (None, line),
+        },
+        None => (None, line),
+    }
+}
+
+fn write_lines(out: &mut dyn Write, lines: &[String], opts: &LogOptions) -> io::Result<()> {
+    for line in lines {
+        let (time, contents) = split_line(line);
+        if let (Some(since), Some(time)) = (opts.since, time) {
+            if time < since {
+                continue;
+            }
+        }
+        if opts.timestamps {
+            writeln!(out, "{}", line)?;
+        } else {
+            writeln!(out, "{}", contents)?;
+        }
+    }
+    out.flush()
+}
+
+/// Reads the complete lines in `file` from `offset`, returning them along
+/// with the offset of the first incomplete line.
+fn read_lines_from(file: &Path, offset: u64) -> io::Result<(Vec<String>, u64)> {
+    let mut file = match File::open(file) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(err) => return Err(err),
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    let complete = data
+        .iter()
+        .rposition(|c| *c == b'\n')
+        .map_or(0, |pos| pos + 1);
+    let lines = String::from_utf8_lossy(&data[..complete])
+        .lines()
+        .map(|line| line.to_string())
+        .collect();
+    Ok((lines, offset + complete as u64))
+}
+
+/// Writes the console log of the VM named `name` to `out`.
+pub fn read_logs(
+    cfg: &KrunvmConfig,
+    name: &str,
+    opts: &LogOptions,
+    out: &mut dyn Write,
+) -> Result<(), KrunvmError> {
+    get_vm(cfg, name)?;
+    let files = log_files(cfg, name);
+    let current = files.last().unwrap().clone();
+    let io_error = |err| KrunvmError::Io(format!("reading {}", current.display()), err);
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for file in files.iter() {
+        let (file_lines, end) = read_lines_from(file, 0).map_err(io_error)?;
+        lines.extend(file_lines);
+        offset = end;
+    }
+    if let Some(tail) = opts.tail {
+        lines.drain(..lines.len().saturating_sub(tail));
+    }
+    write_lines(out, &lines, opts).map_err(io_error)?;
+    if !opts.follow {
+        return Ok(());
+    }
+
+    loop {
+        // Checking the status first, so nothing written before the VM
+        // stopped gets lost.
+        let running = vm_state(cfg, name)?.status == VmStatus::Running;
+
+        let size = fs::metadata(&current).map(|meta| meta.len()).unwrap_or(0);
+        if size < offset {
+            // The log has been rotated, finish reading the previous one.
+            let (lines, _) =
+                read_lines_from(&rotated_log_path(&current, 1), offset).map_err(io_error)?;
+            write_lines(out, &lines, opts).map_err(io_error)?;
+            offset = 0;
+        }
+        let (lines, end) = read_lines_from(&current, offset).map_err(io_error)?;
+        write_lines(out, &lines, opts).map_err(io_error)?;
+        offset = end;
+
+        if !running {
+            break;
+        }
+        thread::sleep(FOLLOW_INTERVAL);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::TestDir;
+
+    #[test]
+    fn parses_relative_times() {
+        let before = now();
+        let since = parse_since("1h30m").unwrap();
+        assert!(since + 5400 >= before && since + 5400 <= now());
+        let since = parse_since("2d10s").unwrap();
+        assert!(since + 172810 >= before && since + 172810 <= now());
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_since("2021-01-31T12:30:15Z").unwrap(), 1612096215);
+        assert_eq!(parse_since("2021-01-31").unwrap(), 1612051200);
+        assert_eq!(parse_since("1970-01-01").unwrap(), 0);
+        assert_eq!(format_timestamp(1612096215), "2021-01-31T12:30:15Z");
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        for value in [
+            "",
+            "10",
+            "m",
+            "10w",
+            "-1h",
+            "2021-13-01",
+            "2021-01-31T12:00:00",
+            "2021-01-31T24:00:00Z",
+        ]
+        .iter()
+        {
+            assert!(parse_since(value).is_err(), "{} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn rotates_full_logs() {
+        let dir = TestDir::new();
+        let path = dir.path().join("vm.log");
+        let mut log = RotatingLog::open(path.clone()).unwrap();
+        let full = vec![b'x'; MAX_LOG_SIZE as usize];
+
+        let lines = |index| {
+            let path = match index {
+                0 => path.clone(),
+                index => rotated_log_path(&path, index),
+            };
+            let data = fs::read_to_string(path).unwrap();
+            data.lines()
+                .map(|line| split_line(line).1.chars().take(7).collect())
+                .collect::<Vec<String>>()
+        };
+
+        log.write(b"first ").unwrap();
+        log.write(&full).unwrap();
+        // Lines are never split across files.
+        log.write(b"\nsecond\n").unwrap();
+        assert_eq!(lines(1), vec!["first x"]);
+        assert_eq!(lines(0), vec!["second"]);
+
+        log.write(b"third\n").unwrap();
+        log.write(&full).unwrap();
+        log.write(b"\nfourth\n").unwrap();
+        log.write(&full).unwrap();
+        log.write(b"\nfifth\n").unwrap();
+        assert_eq!(lines(0), vec!["fifth"]);
+        assert_eq!(lines(1), vec!["fourth", "xxxxxxx"]);
+        assert_eq!(lines(2), vec!["second", "third", "xxxxxxx"]);
+        assert!(!rotated_log_path(&path, 3).exists());
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+
+use crate::backend::Backends;
+use crate::storage::is_valid_vm_name;
+use crate::utils::{format_limit, Ulimit};
+use crate::{KrunvmConfig, KrunvmError, VmConfig};
+
+mod change;
+mod commit;
+mod create;
+mod delete;
+mod exec;
+mod logs;
+mod start;
+mod state;
+mod stop;
+mod transfer;
+
+pub use change::{change_vm, ChangeOptions};
+pub use commit::commit_vm;
+pub use create::{create_vm, prepare_vm, register_vm, CreateOptions, NewVm};
+pub use delete::delete_vm;
+pub use exec::exec_in_vm;
+pub use logs::{parse_since, read_logs, LogOptions};
+pub use start::{start_vm, StartOptions};
+pub use state::vm_state;
+pub use stop::stop_vm;
+pub use transfer::{export_vm, import_vm};
+
+pub const MAX_CPUS: u32 = 8;
+pub const MAX_MEM: u32 = 16384;
+/// Most verbose log level of libkrun, trace.
+pub const MAX_KRUN_LOG_LEVEL: u32 = 5;
+
+pub fn validate_cpus(cpus: u32) -> Result<u32, KrunvmError> {
+    if cpus > MAX_CPUS {
+        return Err(KrunvmError::InvalidInput(format!(
+            "Error: the maximum number of CPUs supported is {}",
+            MAX_CPUS
+        )));
+    }
+    Ok(cpus)
+}
+
+pub fn validate_mem(mem: u32) -> Result<u32, KrunvmError> {
+    if mem > MAX_MEM {
+        return Err(KrunvmError::InvalidInput(format!(
+            "Error: the maximum amount of RAM supported is {} MiB",
+            MAX_MEM
+        )));
+    }
+    Ok(mem)
+}
+
+pub fn validate_krun_log_level(level: u32) -> Result<u32, KrunvmError> {
+    if level > MAX_KRUN_LOG_LEVEL {
+        return Err(KrunvmError::InvalidInput(format!(
+            "Error: the libkrun log level must be between 0 (off) and {} (trace)",
+            MAX_KRUN_LOG_LEVEL
+        )));
+    }
+    Ok(level)
+}
+
+/// Parses the resource limits stored in a VM configuration, checking
+/// their hard limits don't exceed the ones of the host, which the guest
+/// shouldn't be able to go past.
+pub fn validate_ulimits(ulimits: &HashMap<String, String>) -> Result<Vec<Ulimit>, KrunvmError> {
+    let mut parsed = Vec::new();
+    for (resource, limits) in ulimits.iter() {
+        let ulimit: Ulimit = format!("{}={}", resource, limits).parse().map_err(|err| {
+            KrunvmError::InvalidInput(format!("Error: {}={}: {}", resource, limits, err))
+        })?;
+
+        if let Some(host_hard) = host_hard_limit(&ulimit.resource)? {
+            if ulimit.hard > host_hard {
+                return Err(KrunvmError::InvalidInput(format!(
+                    "Error: the hard limit of {} ({}) exceeds the one of the host ({}). \
+                     Raise the host limit first, or choose a lower one",
+                    ulimit.resource,
+                    format_limit(ulimit.hard),
+                    host_hard
+                )));
+            }
+        }
+        parsed.push(ulimit);
+    }
+    parsed.sort_by_key(|ulimit| ulimit.resource_id());
+    Ok(parsed)
+}
+
+/// Returns the hard limit of `resource` for this process, or `None` if
+/// it's unlimited or the host doesn't know about it.
+fn host_hard_limit(resource: &str) -> Result<Option<u64>, KrunvmError> {
+    let host_resource = match resource {
+        "cpu" => libc::RLIMIT_CPU,
+        "fsize" => libc::RLIMIT_FSIZE,
+        "data" => libc::RLIMIT_DATA,
+        "stack" => libc::RLIMIT_STACK,
+        "core" => libc::RLIMIT_CORE,
+        "rss" => libc::RLIMIT_RSS,
+        "nproc" => libc::RLIMIT_NPROC,
+        "nofile" => libc::RLIMIT_NOFILE,
+        "memlock" => libc::RLIMIT_MEMLOCK,
+        "as" => libc::RLIMIT_AS,
+        #[cfg(target_os = "linux")]
+        "locks" => libc::RLIMIT_LOCKS,
+        #[cfg(target_os = "linux")]
+        "sigpending" => libc::RLIMIT_SIGPENDING,
+        #[cfg(target_os = "linux")]
+        "msgqueue" => libc::RLIMIT_MSGQUEUE,
+        #[cfg(target_os = "linux")]
+        "nice" => libc::RLIMIT_NICE,
+        #[cfg(target_os = "linux")]
+        "rtprio" => libc::RLIMIT_RTPRIO,
+        #[cfg(target_os = "linux")]
+        "rttime" => libc::RLIMIT_RTTIME,
+        _ => return Ok(None),
+    };
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(host_resource, &mut limit) } < 0 {
+        return Err(KrunvmError::Io(
+            format!("getting the host limit of {}", resource),
+            std::io::Error::last_os_error(),
+        ));
+    }
+    if limit.rlim_max == libc::RLIM_INFINITY {
+        return Ok(None);
+    }
+    Ok(Some(limit.rlim_max))
+}
+
+/// Checks `name` can be assigned to a new VM.
+pub fn validate_new_name(cfg: &KrunvmConfig, name: &str) -> Result<(), KrunvmError> {
+    if !is_valid_vm_name(name) {
+        return Err(KrunvmError::InvalidInput("Invalid name for VM".to_string()));
+    }
+    if cfg.vmconfig_map.contains_key(name) {
+        return Err(KrunvmError::InvalidInput(format!(
+            "A VM with name {} already exists",
+            name
+        )));
+    }
+    Ok(())
+}
+
+pub fn get_vm<'a>(cfg: &'a KrunvmConfig, name: &str) -> Result<&'a VmConfig, KrunvmError> {
+    cfg.vmconfig_map
+        .get(name)
+        .ok_or_else(|| KrunvmError::VmNotFound(name.to_string()))
+}
+
+/// Returns the raw output of `buildah inspect` for the container backing
+/// the VM. See `InspectInfo` for a parsed version.
+pub fn inspect_vm(
+    cfg: &KrunvmConfig,
+    backends: &dyn Backends,
+    name: &str,
+) -> Result<String, KrunvmError> {
+    let vmcfg = get_vm(cfg, name)?;
+    backends.vm_backend(cfg, vmcfg)?.inspect(&vmcfg.container)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ulimits(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(resource, limits)| (resource.to_string(), limits.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn validates_ulimits() {
+        let parsed = validate_ulimits(&ulimits(&[("nofile", "64:128"), ("cpu", "10:10")])).unwrap();
+        let resources: Vec<&str> = parsed.iter().map(|u| u.resource.as_str()).collect();
+        assert_eq!(resources, vec!["cpu", "nofile"]);
+
+        assert!(matches!(
+            validate_ulimits(&ulimits(&[("nofile", "128:64")])),
+            Err(KrunvmError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_limits_above_the_host_ones() {
+        let host = match host_hard_limit("nofile").unwrap() {
+            Some(host) => host,
+            None => return,
+        };
+        validate_ulimits(&ulimits(&[("nofile", &format!("0:{}", host))])).unwrap();
+        for limits in [format!("0:{}", host + 1), "unlimited".to_string()].iter() {
+            assert!(matches!(
+                validate_ulimits(&ulimits(&[("nofile", limits)])),
+                Err(KrunvmError::InvalidInput(message)) if message.contains("exceeds")
+            ));
+        }
+    }
+}
@@ -0,0 +1,867 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use libc::c_char;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "macos")]
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::logs::ConsoleCapture;
+use super::state::vm_state;
+use super::{get_vm, validate_cpus, validate_krun_log_level, validate_mem, validate_ulimits};
+use crate::agent::{AGENT_GUEST_PATH, AGENT_PORT};
+use crate::backend::{Backends, ContainerBackend};
+use crate::bindings;
+use crate::image::InspectInfo;
+use crate::storage::{load_vm_state, store_vm_state, try_lock_vm, vm_run_dir, VmLock};
+use crate::trace;
+use crate::{KrunvmConfig, KrunvmError, VmConfig, VmState, VmStatus};
+
+/// Name of the guest agent binary, installed next to krunvm.
+const AGENT_BINARY: &str = "krunvm-agent";
+/// Unix socket libkrun connects to the agent's vsock port.
+pub(super) const AGENT_SOCKET: &str = "agent.sock";
+/// Lock file older versions of krunvm created in the root filesystem.
+pub(super) const LEGACY_LOCK_FILE: &str = ".krunvm.lock";
+
+/// Per-start settings that are not persisted in the VM configuration.
+#[derive(Debug, Default)]
+pub struct StartOptions {
+    /// Number of vCPUs, instead of the configured one.
+    pub cpus: Option<u32>,
+    /// Amount of RAM in MiB, instead of the configured one.
+    pub mem: Option<u32>,
+    /// Working directory of the command, instead of the configured one.
+    pub workdir: Option<String>,
+    /// Volumes added to the configured ones, replacing the ones with the
+    /// same host path.
+    pub mapped_volumes: HashMap<String, String>,
+    /// Ports added to the configured ones, replacing the ones with the
+    /// same host port.
+    pub mapped_ports: HashMap<String, String>,
+    /// Command to run inside the VM, instead of the image's default.
+    pub command: Option<String>,
+    /// Arguments to be passed to `command`.
+    pub args: Vec<String>,
+    /// Environment variables overriding the ones stored in the VM
+    /// configuration.
+    pub env: HashMap<String, String>,
+    /// Run the VM in the background, with its console written to a log
+    /// file, instead of attaching it to the terminal.
+    pub detach: bool,
+    /// Also write the console of a VM running in the foreground to its
+    /// log. The guest then no longer sees the terminal as its console.
+    pub log: bool,
+    /// Log level of libkrun, overriding the one stored in the VM
+    /// configuration.
+    pub krun_log_level: Option<u32>,
+    /// Run the command under the guest agent, so `exec_in_vm` can reach
+    /// the VM.
+    pub agent: bool,
+}
+
+fn cstring(value: impl Into<Vec<u8>>) -> Result<CString, KrunvmError> {
+    CString::new(value)
+        .map_err(|_| KrunvmError::InvalidInput("Arguments can't contain NUL bytes".to_string()))
+}
+
+/// Starts the VM named `name`. The guest is attached to the terminal, and
+/// its exit code is returned once it shuts down. With `opts.detach`, it
+/// runs in the background instead, and 0 is returned as soon as it has
+/// been started.
+pub fn start_vm(
+    cfg: &KrunvmConfig,
+    backends: &dyn Backends,
+    name: &str,
+    opts: StartOptions,
+) -> Result<i32, KrunvmError> {
+    // Overrides only apply to this start, so they go to a copy of the VM
+    // configuration.
+    let mut vmcfg = get_vm(cfg, name)?.clone();
+    if let Some(cpus) = opts.cpus {
+        vmcfg.cpus = validate_cpus(cpus)?;
+    }
+    if let Some(mem) = opts.mem {
+        vmcfg.mem = validate_mem(mem)?;
+    }
+    if let Some(workdir) = opts.workdir {
+        vmcfg.workdir = workdir;
+    }
+    vmcfg.mapped_volumes.extend(opts.mapped_volumes);
+    vmcfg.mapped_ports.extend(opts.mapped_ports);
+    let vmcfg = &vmcfg;
+
+    let log_level = validate_krun_log_level(opts.krun_log_level.unwrap_or(vmcfg.krun_log_level))?;
+    // libkrun expects each limit as "ID=SOFT:HARD", with the identifier of
+    // the resource on Linux.
+    let rlimits = validate_ulimits(&vmcfg.ulimits)?
+        .iter()
+        .map(|ulimit| {
+            cstring(format!(
+                "{}={}:{}",
+                ulimit.resource_id(),
+                ulimit.soft,
+                ulimit.hard
+            ))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let backend = backends.vm_backend(cfg, vmcfg)?;
+    let agent = if opts.agent {
+        Some(find_agent()?)
+    } else {
+        None
+    };
+
+    // Taken before touching the container, which may belong to a running
+    // instance of the VM.
+    let _lock = lock_vm(cfg, name)?;
+
+    backend.umount(&vmcfg.container)?;
+    let rootfs = backend.mount(&vmcfg.container)?;
+    // Older versions of krunvm kept the lock in the root filesystem.
+    let _ = fs::remove_file(format!("{}/{}", rootfs, LEGACY_LOCK_FILE));
+
+    // Without a command in the command line, fall back to the one stored in
+    // the VM configuration, if any.
+    let (command, args) = match opts.command {
+        Some(command) => (Some(command), opts.args),
+        None if !vmcfg.command.is_empty() => (Some(vmcfg.command.clone()), vmcfg.args.clone()),
+        None => (None, Vec::new()),
+    };
+
+    let mut cmdline: Vec<String> = command.iter().cloned().collect();
+    if command.is_some() {
+        cmdline.extend(args.iter().cloned());
+    }
+
+    // When requested, the agent runs the command itself, serving
+    // `krunvm exec` requests meanwhile.
+    let mut agent_socket = None;
+    let (command, args) = if install_agent(&rootfs, agent.as_deref())? {
+        if cmdline.is_empty() {
+            cmdline = image_command(&rootfs);
+        }
+        agent_socket = Some(vm_run_dir(&cfg.config_dir, name).join(AGENT_SOCKET));
+        (Some(AGENT_GUEST_PATH.to_string()), cmdline.clone())
+    } else {
+        (command, args)
+    };
+
+    let vm_args: Vec<CString> = if command.is_some() {
+        args.into_iter().map(cstring).collect::<Result<_, _>>()?
+    } else {
+        Vec::new()
+    };
+
+    let env_pairs = build_env(vmcfg, opts.env)?;
+
+    set_rlimits()?;
+
+    // We hold the VM lock, so any socket left around is stale.
+    if let Some(socket) = agent_socket.as_ref() {
+        fs::create_dir_all(socket.parent().unwrap())
+            .and_then(|_| match fs::remove_file(socket) {
+                Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            })
+            .map_err(|err| KrunvmError::Io(format!("preparing {}", socket.display()), err))?;
+    }
+
+    let vm = PreparedVm {
+        vmcfg,
+        rootfs: &rootfs,
+        command: command.as_deref(),
+        args: vm_args,
+        env_pairs,
+        cmdline,
+        agent_socket,
+        log_level,
+        rlimits,
+    };
+    if opts.detach {
+        detach_vm(cfg, backend.as_ref(), vm)?;
+        return Ok(0);
+    }
+
+    // The console stays attached to the terminal, so the guest sees a TTY.
+    // Copying it to the log as well means going through a FIFO instead.
+    if !opts.log {
+        return supervise(cfg, backend.as_ref(), vm, None, None);
+    }
+    let mut console = ConsoleCapture::new(cfg, name, true)?;
+    let ret = supervise(cfg, backend.as_ref(), vm, None, Some(&mut console));
+    if let Err(err) = ret.as_ref() {
+        console.log_message(&err.to_string());
+    }
+    console.finish();
+    ret
+}
+
+/// Everything `exec_vm` needs to run a VM, prepared before forking so
+/// errors can still be reported to the user.
+struct PreparedVm<'a> {
+    vmcfg: &'a VmConfig,
+    rootfs: &'a str,
+    command: Option<&'a str>,
+    args: Vec<CString>,
+    env_pairs: Vec<CString>,
+    /// Command and arguments run in the VM, as recorded in its state.
+    cmdline: Vec<String>,
+    /// Unix socket connected to the agent, if it was installed.
+    agent_socket: Option<PathBuf>,
+    log_level: u32,
+    /// Resource limits of the guest, in the format libkrun expects.
+    rlimits: Vec<CString>,
+}
+
+/// Reads the `N` bytes at `offset` of `data`, if there are enough.
+fn bytes_at<const N: usize>(data: &[u8], offset: usize) -> Option<[u8; N]> {
+    data.get(offset..offset.checked_add(N)?)?.try_into().ok()
+}
+
+/// Checks `data` is a statically linked, 64-bit little endian ELF
+/// executable for the architecture of the guest, which matches the one of
+/// the host. Returns why it isn't otherwise.
+fn check_agent_binary(data: &[u8]) -> Result<(), String> {
+    const PT_INTERP: u32 = 3;
+    let machine = match env::consts::ARCH {
+        "x86_64" => 62,
+        "aarch64" => 183,
+        arch => return Err(format!("guests on {} aren't supported", arch)),
+    };
+
+    if data.get(..4) != Some(b"\x7fELF".as_ref()) || data.get(4..6) != Some([2, 1].as_ref()) {
+        return Err("it isn't a 64-bit little endian ELF binary".to_string());
+    }
+    let header = (|| {
+        let e_machine = u16::from_le_bytes(bytes_at(data, 0x12)?);
+        let e_phoff = u64::from_le_bytes(bytes_at(data, 0x20)?);
+        let e_phentsize = u16::from_le_bytes(bytes_at(data, 0x36)?);
+        let e_phnum = u16::from_le_bytes(bytes_at(data, 0x38)?);
+        Some((
+            e_machine,
+            usize::try_from(e_phoff).ok()?,
+            e_phentsize,
+            e_phnum,
+        ))
+    })();
+    let (e_machine, e_phoff, e_phentsize, e_phnum) =
+        header.ok_or_else(|| "its ELF header is truncated".to_string())?;
+    if e_machine != machine {
+        return Err(format!("it isn't built for {}", env::consts::ARCH));
+    }
+
+    for index in 0..e_phnum as usize {
+        let offset = index
+            .checked_mul(e_phentsize as usize)
+            .and_then(|offset| offset.checked_add(e_phoff));
+        let p_type = offset
+            .and_then(|offset| bytes_at(data, offset))
+            .map(u32::from_le_bytes)
+            .ok_or_else(|| "its program headers are truncated".to_string())?;
+        // Dynamically linked binaries name their loader, which the guest
+        // may not have.
+        if p_type == PT_INTERP {
+            return Err("it isn't statically linked".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the guest agent shipped next to the krunvm binary, making sure
+/// it can run in the guest.
+fn find_agent() -> Result<PathBuf, KrunvmError> {
+    let agent = env::current_exe()
+        .map(|exe| exe.with_file_name(AGENT_BINARY))
+        .map_err(|err| KrunvmError::Io("locating the guest agent".to_string(), err))?;
+    let check = match fs::read(&agent) {
+        Ok(data) => check_agent_binary(&data),
+        Err(err) => Err(err.to_string()),
+    };
+    check.map_err(|reason| {
+        KrunvmError::InvalidInput(format!(
+            "The guest agent {} can't be used: {}. Build it with \"make agent\"",
+            agent.display(),
+            reason
+        ))
+    })?;
+    Ok(agent)
+}
+
+/// Copies `agent`, if any, into the root filesystem, returning whether it
+/// was.
+fn install_agent(rootfs: &str, agent: Option<&Path>) -> Result<bool, KrunvmError> {
+    let dest = format!("{}{}", rootfs, AGENT_GUEST_PATH);
+    // A previous run may have been killed before removing it.
+    let _ = fs::remove_file(&dest);
+    let agent = match agent {
+        Some(agent) => agent,
+        None => return Ok(false),
+    };
+
+    fs::copy(agent, &dest).map_err(|err| KrunvmError::Io(format!("installing {}", dest), err))?;
+    Ok(true)
+}
+
+/// Returns the command the image of the VM defines, which libkrun would
+/// run if no other one was given, or a shell if there is none.
+fn image_command(rootfs: &str) -> Vec<String> {
+    let mut command = fs::read_to_string(format!("{}/.krun_config.json", rootfs))
+        .ok()
+        .and_then(|data| InspectInfo::parse(&data).ok())
+        .map(|info| {
+            let config = info.image.config;
+            [config.entrypoint, config.cmd].concat()
+        })
+        .unwrap_or_default();
+    if command.is_empty() {
+        command.push("/bin/sh".to_string());
+    }
+    command
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+fn fork(op: &str) -> Result<libc::pid_t, KrunvmError> {
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(KrunvmError::Io(op.to_string(), Error::last_os_error()));
+    }
+    Ok(pid)
+}
+
+fn store_state(cfg: &KrunvmConfig, name: &str, state: &VmState) -> Result<(), KrunvmError> {
+    store_vm_state(&cfg.config_dir, name, state).map_err(KrunvmError::Config)
+}
+
+/// Creates a pipe, returning its read and write ends.
+fn pipe() -> Result<(File, File), KrunvmError> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(KrunvmError::Io(
+            "creating pipe".to_string(),
+            Error::last_os_error(),
+        ));
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Runs the VM in the background and prints its name once it's running.
+///
+/// The VM is supervised by a process detached from the terminal, which
+/// captures the console of the VM into its log.
+fn detach_vm(
+    cfg: &KrunvmConfig,
+    backend: &dyn ContainerBackend,
+    vm: PreparedVm,
+) -> Result<(), KrunvmError> {
+    let name = &vm.vmcfg.name;
+    let mut console = ConsoleCapture::new(cfg, name, false)?;
+
+    // The supervisor closes its end of the pipe once the VM state has been
+    // recorded, so the VM can be found as soon as we return.
+    let (mut ready_rx, ready_tx) = pipe()?;
+
+    let launched_at = now();
+    if fork("forking supervisor")? == 0 {
+        drop(ready_rx);
+        unsafe { libc::setsid() };
+        // Anything the supervisor has to say goes to the log.
+        if let Ok(devnull) = OpenOptions::new().read(true).write(true).open("/dev/null") {
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO].iter() {
+                unsafe { libc::dup2(devnull.as_raw_fd(), *fd) };
+            }
+        }
+        let ret = supervise(cfg, backend, vm, Some(ready_tx), Some(&mut console));
+        let code = match ret {
+            Ok(code) => code,
+            Err(err) => {
+                console.log_message(&err.to_string());
+                1
+            }
+        };
+        console.finish();
+        std::process::exit(code);
+    }
+
+    drop(ready_tx);
+    let mut buf = [0u8; 1];
+    let _ = ready_rx.read(&mut buf);
+
+    // The VM may already have exited by now, which is fine as long as it
+    // did successfully.
+    let state = vm_state(cfg, name)?;
+    if state.started_at < launched_at {
+        return Err(KrunvmError::InvalidInput(format!(
+            "The microVM failed to start, see `krunvm logs {}` for details",
+            name
+        )));
+    }
+    match state.exit_status {
+        Some(code) if code != 0 && state.status != VmStatus::Running => {
+            Err(KrunvmError::InvalidInput(format!(
+                "The microVM exited with status {}, see `krunvm logs {}` for details",
+                code, name
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Waits for the child `pid` to exit, returning whether it exited by
+/// itself along with its exit code, or the signal that killed it
+/// otherwise. With `WNOWAIT`, the child is left to be reaped later.
+fn wait_vm(pid: libc::pid_t, flags: libc::c_int) -> Result<(bool, i32), KrunvmError> {
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    while unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | flags,
+        )
+    } < 0
+    {
+        let err = Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(KrunvmError::Io("waiting for the VM".to_string(), err));
+        }
+    }
+    Ok((info.si_code == libc::CLD_EXITED, unsafe {
+        info.si_status()
+    }))
+}
+
+/// Runs the VM in a child process and waits for it to exit, recording its
+/// state along the way and unmounting the container afterwards. Returns
+/// the exit code of the VM.
+///
+/// `ready_tx` is closed once the VM has been recorded as running. The
+/// console and any output of the VM process are captured by `console`, if
+/// given, and otherwise left on our standard output and error.
+fn supervise(
+    cfg: &KrunvmConfig,
+    backend: &dyn ContainerBackend,
+    vm: PreparedVm,
+    ready_tx: Option<File>,
+    mut console: Option<&mut ConsoleCapture>,
+) -> Result<i32, KrunvmError> {
+    // The VM process reports why it failed to set up the VM through it.
+    let (mut error_rx, mut error_tx) = pipe()?;
+    let pid = fork("forking VM process")?;
+    if pid == 0 {
+        drop(ready_tx);
+        drop(error_rx);
+        let mut output = None;
+        if let Some(console) = console.as_ref() {
+            unsafe {
+                libc::dup2(console.writer_fd(), libc::STDOUT_FILENO);
+                libc::dup2(console.writer_fd(), libc::STDERR_FILENO);
+            }
+            output = console.output();
+        }
+        // libkrun exits the process itself when the guest shuts down, so
+        // this only returns on failure.
+        if let Err(err) = unsafe { exec_vm(&vm, output) } {
+            let _ = error_tx.write_all(err.to_string().as_bytes());
+        }
+        std::process::exit(1);
+    }
+    drop(error_tx);
+
+    // Threads don't survive `fork`, so the console is only copied once the
+    // VM process has been forked.
+    if let Some(console) = console.as_mut() {
+        console.start();
+    }
+
+    // Interrupt keys in the terminal are meant for the guest, and we still
+    // have to record its exit.
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+        libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+    }
+
+    let name = &vm.vmcfg.name;
+    let mut state = VmState {
+        status: VmStatus::Running,
+        pid,
+        started_at: now(),
+        exit_status: None,
+        stop_requested: false,
+        command: vm.cmdline,
+    };
+    store_state(cfg, name, &state)?;
+    drop(ready_tx);
+
+    // The VM process is only reaped once its exit has been recorded, so
+    // `stop_vm` can't signal a reused pid while the VM is shown running.
+    let (exited, status) = wait_vm(pid, libc::WNOWAIT)?;
+    let mut failure = String::new();
+    let _ = error_rx.read_to_string(&mut failure);
+
+    // Followers of the log stop once the VM is no longer running, make
+    // sure they get everything.
+    if let Some(console) = console.as_mut() {
+        console.drain();
+    }
+
+    // `stop_vm` flags the state before signaling the VM.
+    if let Ok(Some(current)) = load_vm_state(&cfg.config_dir, name) {
+        state.stop_requested = current.stop_requested;
+    }
+    let code = if exited {
+        state.status = VmStatus::Stopped;
+        status
+    } else {
+        state.status = if state.stop_requested {
+            VmStatus::Stopped
+        } else {
+            VmStatus::Crashed
+        };
+        128 + status
+    };
+    state.exit_status = Some(code);
+    let stored = store_state(cfg, name, &state);
+    wait_vm(pid, 0)?;
+    stored?;
+    if let Some(socket) = vm.agent_socket.as_ref() {
+        let _ = fs::remove_file(socket);
+        let _ = fs::remove_file(format!("{}{}", vm.rootfs, AGENT_GUEST_PATH));
+    }
+
+    let umounted = backend.umount(&vm.vmcfg.container);
+    if !failure.is_empty() {
+        return Err(KrunvmError::VmSetup(failure));
+    }
+    umounted?;
+    Ok(code)
+}
+
+/// Builds the guest environment. Variables given for this start take
+/// precedence over the ones stored in the VM configuration, which in turn
+/// take precedence over the HOSTNAME and HOME defaults.
+fn build_env(
+    vmcfg: &VmConfig,
+    overrides: HashMap<String, String>,
+) -> Result<Vec<CString>, KrunvmError> {
+    let mut env = HashMap::new();
+    env.insert("HOSTNAME".to_string(), vmcfg.name.clone());
+    env.insert("HOME".to_string(), "/root".to_string());
+    env.extend(vmcfg.env.clone());
+    env.extend(overrides);
+
+    env.into_iter()
+        .map(|(key, value)| cstring(format!("{}={}", key, value)))
+        .collect()
+}
+
+/// Turns the return value of the libkrun function `call`, made while
+/// `op`, into a result.
+fn check_krun(call: &str, op: &'static str, ret: i32) -> Result<(), KrunvmError> {
+    trace::event(trace::DEBUG, "libkrun", &[("call", &call), ("ret", &ret)]);
+    if ret < 0 {
+        return Err(KrunvmError::Libkrun { op, code: ret });
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn map_volumes(_ctx: u32, vmcfg: &VmConfig, rootfs: &str) -> Result<(), KrunvmError> {
+    for (host_path, guest_path) in vmcfg.mapped_volumes.iter() {
+        let host_dir = cstring(host_path.to_string())?;
+        let guest_dir = cstring(format!("{}{}", rootfs, guest_path))?;
+
+        let ret = unsafe { libc::mkdir(guest_dir.as_ptr(), 0o755) };
+        if ret < 0 && Error::last_os_error().kind() != ErrorKind::AlreadyExists {
+            return Err(KrunvmError::Io(
+                format!("creating directory {:?}", guest_dir),
+                Error::last_os_error(),
+            ));
+        }
+        unsafe { libc::umount(guest_dir.as_ptr()) };
+        let ret = unsafe {
+            libc::mount(
+                host_dir.as_ptr(),
+                guest_dir.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND | libc::MS_REC,
+                std::ptr::null(),
+            )
+        };
+        if ret < 0 {
+            return Err(KrunvmError::Io(
+                format!("mounting volume {}", guest_path),
+                Error::last_os_error(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn map_volumes(
+    ctx: u32,
+    vmcfg: &VmConfig,
+    rootfs: &str,
+) -> Result<Vec<(String, String)>, KrunvmError> {
+    let mut mounts = Vec::new();
+    for (idx, (host_path, guest_path)) in vmcfg.mapped_volumes.iter().enumerate() {
+        let full_guest = format!("{}{}", &rootfs, guest_path);
+        let full_guest_path = Path::new(&full_guest);
+        if !full_guest_path.exists() {
+            std::fs::create_dir(full_guest_path).map_err(|err| {
+                KrunvmError::Io(format!("creating guest_path {}", full_guest), err)
+            })?;
+        }
+        let tag = format!("krunvm{}", idx);
+        let c_tag = cstring(tag.as_str())?;
+        let c_host = cstring(host_path.as_str())?;
+        let ret = unsafe { bindings::krun_add_virtiofs(ctx, c_tag.as_ptr(), c_host.as_ptr()) };
+        check_krun("krun_add_virtiofs", "setting VM mapped volume", ret)?;
+        mounts.push((tag, guest_path.to_string()));
+    }
+    Ok(mounts)
+}
+
+unsafe fn exec_vm(vm: &PreparedVm, console_output: Option<&Path>) -> Result<(), KrunvmError> {
+    let vmcfg = vm.vmcfg;
+    let rootfs = vm.rootfs;
+    let cmd = vm.command;
+    let args = &vm.args;
+    let log_level = vm.log_level;
+
+    trace::event(
+        trace::TRACE,
+        "libkrun-config",
+        &[
+            ("cpus", &vmcfg.cpus),
+            ("mem", &vmcfg.mem),
+            ("root", &rootfs),
+            ("workdir", &vmcfg.workdir),
+            ("exec", &cmd.unwrap_or_default()),
+            ("volumes", &format!("{:?}", vmcfg.mapped_volumes)),
+            ("ports", &format!("{:?}", vmcfg.mapped_ports)),
+            ("ulimits", &format!("{:?}", vmcfg.ulimits)),
+            ("log_level", &log_level),
+        ],
+    );
+
+    let ret = bindings::krun_set_log_level(log_level);
+    check_krun("krun_set_log_level", "setting libkrun log level", ret)?;
+
+    let ctx = bindings::krun_create_ctx();
+    check_krun("krun_create_ctx", "creating VM context", ctx)?;
+    let ctx = ctx as u32;
+
+    let ret = bindings::krun_set_vm_config(ctx, vmcfg.cpus as u8, vmcfg.mem);
+    check_krun("krun_set_vm_config", "setting VM config", ret)?;
+
+    if !vm.rlimits.is_empty() {
+        let mut rlimits: Vec<*const c_char> = vm.rlimits.iter().map(|r| r.as_ptr()).collect();
+        rlimits.push(std::ptr::null());
+        let ret = bindings::krun_set_rlimits(ctx, rlimits.as_ptr());
+        check_krun("krun_set_rlimits", "setting VM rlimits", ret)?;
+    }
+
+    let c_rootfs = cstring(rootfs)?;
+    let ret = bindings::krun_set_root(ctx, c_rootfs.as_ptr());
+    check_krun("krun_set_root", "setting VM rootfs", ret)?;
+
+    #[cfg(target_os = "linux")]
+    map_volumes(ctx, vmcfg, rootfs)?;
+    #[cfg(target_os = "macos")]
+    let virtiofs_mounts = map_volumes(ctx, vmcfg, rootfs)?;
+    #[cfg(target_os = "macos")]
+    let mount_wrapper = build_mount_wrapper(rootfs, cmd, &vmcfg.workdir, args, &virtiofs_mounts)?;
+
+    let mut ports = Vec::new();
+    for (host_port, guest_port) in vmcfg.mapped_ports.iter() {
+        let map = format!("{}:{}", host_port, guest_port);
+        ports.push(cstring(map)?);
+    }
+    let mut ps: Vec<*const c_char> = Vec::new();
+    for port in ports.iter() {
+        ps.push(port.as_ptr());
+    }
+    ps.push(std::ptr::null());
+
+    let ret = bindings::krun_set_port_map(ctx, ps.as_ptr());
+    check_krun("krun_set_port_map", "setting VM port map", ret)?;
+
+    if let Some(path) = console_output {
+        let c_path = cstring(path.as_os_str().as_bytes())?;
+        let ret = bindings::krun_set_console_output(ctx, c_path.as_ptr());
+        check_krun("krun_set_console_output", "setting VM console output", ret)?;
+    }
+
+    if let Some(socket) = vm.agent_socket.as_ref() {
+        let c_socket = cstring(socket.as_os_str().as_bytes())?;
+        let ret = bindings::krun_add_vsock_port2(ctx, AGENT_PORT, c_socket.as_ptr(), true);
+        check_krun("krun_add_vsock_port2", "setting VM agent vsock port", ret)?;
+    }
+
+    if !vmcfg.workdir.is_empty() {
+        let c_workdir = cstring(vmcfg.workdir.clone())?;
+        let ret = bindings::krun_set_workdir(ctx, c_workdir.as_ptr());
+        check_krun("krun_set_workdir", "setting VM workdir", ret)?;
+    }
+
+    let mut env: Vec<*const c_char> = Vec::new();
+    for value in vm.env_pairs.iter() {
+        env.push(value.as_ptr());
+    }
+    env.push(std::ptr::null());
+
+    #[cfg(target_os = "macos")]
+    {
+        if let Some((helper_path, helper_args)) = mount_wrapper {
+            let mut argv: Vec<*const c_char> = helper_args.iter().map(|a| a.as_ptr()).collect();
+            argv.push(std::ptr::null());
+            let ret =
+                bindings::krun_set_exec(ctx, helper_path.as_ptr(), argv.as_ptr(), env.as_ptr());
+            check_krun("krun_set_exec", "setting VM config", ret)?;
+        } else if let Some(cmd) = cmd {
+            let mut argv: Vec<*const c_char> = Vec::new();
+            for a in args.iter() {
+                argv.push(a.as_ptr());
+            }
+            argv.push(std::ptr::null());
+
+            let c_cmd = cstring(cmd)?;
+            let ret = bindings::krun_set_exec(ctx, c_cmd.as_ptr(), argv.as_ptr(), env.as_ptr());
+            check_krun("krun_set_exec", "setting VM config", ret)?;
+        } else {
+            let ret = bindings::krun_set_env(ctx, env.as_ptr());
+            check_krun("krun_set_env", "setting VM environment variables", ret)?;
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        if let Some(cmd) = cmd {
+            let mut argv: Vec<*const c_char> = Vec::new();
+            for a in args.iter() {
+                argv.push(a.as_ptr());
+            }
+            argv.push(std::ptr::null());
+
+            let c_cmd = cstring(cmd)?;
+            let ret = bindings::krun_set_exec(ctx, c_cmd.as_ptr(), argv.as_ptr(), env.as_ptr());
+            check_krun("krun_set_exec", "setting VM config", ret)?;
+        } else {
+            let ret = bindings::krun_set_env(ctx, env.as_ptr());
+            check_krun("krun_set_env", "setting VM environment variables", ret)?;
+        }
+    }
+
+    // On success this doesn't return, so log the call beforehand.
+    trace::event(trace::DEBUG, "libkrun", &[("call", &"krun_start_enter")]);
+    let ret = bindings::krun_start_enter(ctx);
+    check_krun("krun_start_enter", "starting VM", ret)
+}
+
+#[cfg(target_os = "macos")]
+fn build_mount_wrapper(
+    rootfs: &str,
+    cmd: Option<&str>,
+    workdir: &str,
+    args: &[CString],
+    mounts: &[(String, String)],
+) -> Result<Option<(CString, Vec<CString>)>, KrunvmError> {
+    if mounts.is_empty() {
+        return Ok(None);
+    }
+
+    let helper_path = write_mount_script(rootfs, workdir, mounts)
+        .map_err(|err| KrunvmError::Io("creating mount helper script".to_string(), err))?;
+
+    let mut exec_args: Vec<CString> = Vec::new();
+    let command = cmd.unwrap_or("/bin/sh");
+    exec_args.push(cstring(command)?);
+    exec_args.extend(args.iter().cloned());
+
+    let helper_cstr = cstring(helper_path)?;
+    Ok(Some((helper_cstr, exec_args)))
+}
+
+#[cfg(target_os = "macos")]
+fn write_mount_script(
+    rootfs: &str,
+    workdir: &str,
+    mounts: &[(String, String)],
+) -> Result<String, std::io::Error> {
+    let host_path = format!("{}/.krunvm-mount.sh", rootfs);
+    let guest_path = "/.krunvm-mount.sh".to_string();
+
+    let mut file = File::create(&host_path)?;
+
+    writeln!(file, "#!/bin/sh")?;
+    writeln!(file, "set -e")?;
+    for (tag, guest_path) in mounts {
+        writeln!(file, "mount -t virtiofs {} {}", tag, guest_path)?;
+    }
+    if !workdir.is_empty() {
+        writeln!(file, "cd {}", workdir)?;
+    }
+    writeln!(file, "exec \"$@\"")?;
+
+    let perms = fs::Permissions::from_mode(0o755);
+    fs::set_permissions(&host_path, perms)?;
+
+    Ok(guest_path)
+}
+
+fn set_rlimits() -> Result<(), KrunvmError> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    let ret = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    if ret < 0 {
+        return Err(KrunvmError::Io(
+            "getting RLIMIT_NOFILE value".to_string(),
+            std::io::Error::last_os_error(),
+        ));
+    }
+
+    limit.rlim_cur = limit.rlim_max;
+    let ret = unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) };
+    if ret < 0 {
+        return Err(KrunvmError::Io(
+            "setting RLIMIT_NOFILE value".to_string(),
+            std::io::Error::last_os_error(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Takes the lock of the VM named `name`, failing if it's running or
+/// another operation holds it.
+pub(super) fn lock_vm(cfg: &KrunvmConfig, name: &str) -> Result<VmLock, KrunvmError> {
+    try_lock_vm(&cfg.config_dir, name)
+        .map_err(|err| KrunvmError::Io(format!("locking microVM {}", name), err))?
+        .ok_or_else(|| {
+            KrunvmError::InvalidInput(format!(
+                "microVM {} is running, or in use by another instance of krunvm",
+                name
+            ))
+        })
+}
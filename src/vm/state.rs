@@ -0,0 +1,31 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::get_vm;
+use crate::storage::{load_vm_state, try_lock_vm};
+use crate::{KrunvmConfig, KrunvmError, VmState, VmStatus};
+
+/// Returns whether the lock of the VM named `name` is held, which means
+/// the processes running and supervising it haven't exited yet.
+pub(super) fn is_locked(cfg: &KrunvmConfig, name: &str) -> Result<bool, KrunvmError> {
+    let lock = try_lock_vm(&cfg.config_dir, name)
+        .map_err(|err| KrunvmError::Io(format!("checking the lock of microVM {}", name), err))?;
+    Ok(lock.is_none())
+}
+
+/// Returns the runtime state of the VM named `name`. VMs recorded as
+/// running whose lock isn't held anymore are reported as crashed, as
+/// whatever was supervising them couldn't record their exit. The
+/// recorded pid can only be trusted while the VM is reported as running,
+/// as it may have been reused since.
+pub fn vm_state(cfg: &KrunvmConfig, name: &str) -> Result<VmState, KrunvmError> {
+    get_vm(cfg, name)?;
+
+    let mut state = load_vm_state(&cfg.config_dir, name)
+        .map_err(KrunvmError::Config)?
+        .unwrap_or_default();
+    if state.status == VmStatus::Running && !is_locked(cfg, name)? {
+        state.status = VmStatus::Crashed;
+    }
+    Ok(state)
+}
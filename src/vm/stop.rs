@@ -0,0 +1,164 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::{self, Error};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::get_vm;
+use super::start::AGENT_SOCKET;
+use super::state::{is_locked, vm_state};
+use crate::agent::{write_frame, SHUTDOWN};
+use crate::storage::{store_vm_state, vm_run_dir};
+use crate::{KrunvmConfig, KrunvmError, VmStatus};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits up to `timeout` for the processes running and supervising the VM
+/// named `name` to exit, returning whether they did.
+fn wait_exit(cfg: &KrunvmConfig, name: &str, timeout: Duration) -> Result<bool, KrunvmError> {
+    let deadline = Instant::now() + timeout;
+    while is_locked(cfg, name)? {
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(true)
+}
+
+/// Asks the guest agent listening on `socket` to terminate the command of
+/// the VM, which powers the guest off once it exits.
+fn request_shutdown(socket: &Path) -> io::Result<()> {
+    let mut conn = UnixStream::connect(socket)?;
+    write_frame(&mut conn, SHUTDOWN, &[])
+}
+
+/// Opens a pidfd for the process `pid`, returning `None` where they aren't
+/// supported.
+#[cfg(target_os = "linux")]
+fn open_pidfd(pid: libc::pid_t) -> io::Result<Option<File>> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        let err = Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENOSYS) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(unsafe { File::from_raw_fd(fd as i32) }))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_pidfd(_pid: libc::pid_t) -> io::Result<Option<File>> {
+    Ok(None)
+}
+
+#[cfg(target_os = "linux")]
+fn send_signal(pidfd: &File, signal: libc::c_int) -> libc::c_long {
+    use std::os::unix::io::AsRawFd;
+
+    unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            signal,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_signal(_pidfd: &File, _signal: libc::c_int) -> libc::c_long {
+    unreachable!()
+}
+
+/// Sends `signal` to the process `pid` running the VM named `name`, if it
+/// still is.
+///
+/// The supervisor only reaps the VM process once it has recorded its exit,
+/// so a pid recorded for a running VM can't have been reused. The pid is
+/// pinned with a pidfd before checking that, where they're supported.
+fn signal_vm(
+    cfg: &KrunvmConfig,
+    name: &str,
+    pid: libc::pid_t,
+    signal: libc::c_int,
+) -> Result<(), KrunvmError> {
+    let op = || format!("signaling microVM {}", name);
+    let pidfd = match open_pidfd(pid) {
+        Ok(pidfd) => pidfd,
+        Err(err) if err.raw_os_error() == Some(libc::ESRCH) => return Ok(()),
+        Err(err) => return Err(KrunvmError::Io(op(), err)),
+    };
+
+    let state = vm_state(cfg, name)?;
+    if state.status != VmStatus::Running || state.pid != pid {
+        return Ok(());
+    }
+    let ret = match pidfd.as_ref() {
+        Some(pidfd) => send_signal(pidfd, signal),
+        None => unsafe { libc::kill(pid, signal) }.into(),
+    };
+    if ret < 0 {
+        let err = Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            return Err(KrunvmError::Io(op(), err));
+        }
+    }
+    Ok(())
+}
+
+/// Stops the running VM named `name`, killing it if it's still running
+/// after `timeout`. VMs started with the guest agent are shut down by
+/// terminating their command. libkrun offers no way to shut down other
+/// ones cleanly, so the VMM process gets SIGTERM, which ends the guest
+/// abruptly.
+///
+/// Returns once the supervisor of the VM has recorded its exit and
+/// unmounted its container, along with a warning if the VM had to be
+/// killed.
+pub fn stop_vm(
+    cfg: &KrunvmConfig,
+    name: &str,
+    timeout: Duration,
+) -> Result<Vec<String>, KrunvmError> {
+    get_vm(cfg, name)?;
+    let mut state = vm_state(cfg, name)?;
+    if state.status != VmStatus::Running {
+        return Err(KrunvmError::InvalidInput(format!(
+            "microVM {} is not running",
+            name
+        )));
+    }
+    let pid = state.pid;
+
+    state.stop_requested = true;
+    store_vm_state(&cfg.config_dir, name, &state).map_err(KrunvmError::Config)?;
+
+    let socket = vm_run_dir(&cfg.config_dir, name).join(AGENT_SOCKET);
+    if !socket.exists() || request_shutdown(&socket).is_err() {
+        signal_vm(cfg, name, pid, libc::SIGTERM)?;
+    }
+    if wait_exit(cfg, name, timeout)? {
+        return Ok(Vec::new());
+    }
+    signal_vm(cfg, name, pid, libc::SIGKILL)?;
+    if !wait_exit(cfg, name, timeout)? {
+        return Err(KrunvmError::InvalidInput(format!(
+            "microVM {} is still running after being killed",
+            name
+        )));
+    }
+    Ok(vec![format!(
+        "microVM {} didn't stop after {} seconds and was killed",
+        name,
+        timeout.as_secs()
+    )])
+}
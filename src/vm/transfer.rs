@@ -0,0 +1,131 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::{create_vm, get_vm, CreateOptions};
+use crate::backend::Backends;
+use crate::migration::CONFIG_VERSION;
+use crate::storage::is_valid_vm_name;
+use crate::{KrunvmConfig, KrunvmError, VmConfig};
+
+/// A VM definition that can be moved between machines. The buildah
+/// container is not part of it, as it gets recreated from `vm.image` on
+/// import.
+#[derive(Debug, Serialize, Deserialize)]
+struct VmDefinition {
+    version: u8,
+    vm: VmConfig,
+}
+
+/// Serializes the definition of the VM named `name`. `image` overrides
+/// the image the VM was created from, which older versions of krunvm
+/// didn't record.
+pub fn export_vm(
+    cfg: &KrunvmConfig,
+    name: &str,
+    image: Option<String>,
+) -> Result<String, KrunvmError> {
+    let vmcfg = get_vm(cfg, name)?;
+
+    let image = image.unwrap_or_else(|| vmcfg.image.clone());
+    if image.is_empty() {
+        return Err(KrunvmError::InvalidInput(format!(
+            "The image VM {} was created from is unknown, please specify it with --image",
+            name
+        )));
+    }
+
+    let definition = VmDefinition {
+        version: CONFIG_VERSION,
+        vm: VmConfig {
+            name: vmcfg.name.clone(),
+            image,
+            image_id: String::new(),
+            cpus: vmcfg.cpus,
+            mem: vmcfg.mem,
+            container: String::new(),
+            backend: String::new(),
+            workdir: vmcfg.workdir.clone(),
+            dns: vmcfg.dns.clone(),
+            mapped_volumes: vmcfg.mapped_volumes.clone(),
+            mapped_ports: vmcfg.mapped_ports.clone(),
+            env: vmcfg.env.clone(),
+            ulimits: vmcfg.ulimits.clone(),
+            command: vmcfg.command.clone(),
+            args: vmcfg.args.clone(),
+            krun_log_level: vmcfg.krun_log_level,
+        },
+    };
+
+    toml::to_string(&definition)
+        .map_err(|err| KrunvmError::InvalidInput(format!("Error serializing VM {}: {}", name, err)))
+}
+
+/// Returns `name` if no VM is using it, or the first `name-N` that is free.
+fn free_name(cfg: &KrunvmConfig, name: &str) -> String {
+    if !cfg.vmconfig_map.contains_key(name) {
+        return name.to_string();
+    }
+    (1..)
+        .map(|idx| format!("{}-{}", name, idx))
+        .find(|candidate| !cfg.vmconfig_map.contains_key(candidate))
+        .unwrap()
+}
+
+/// Registers a new VM from a definition produced by `export_vm`, creating
+/// its buildah container from the recorded image. The VM is named `name`
+/// if given, or after the exported VM, with a suffix if needed to avoid
+/// conflicts. Returns the name of the new VM.
+pub fn import_vm(
+    cfg: &mut KrunvmConfig,
+    backends: &dyn Backends,
+    data: &str,
+    name: Option<String>,
+) -> Result<String, KrunvmError> {
+    let definition: VmDefinition = toml::from_str(data)
+        .map_err(|err| KrunvmError::InvalidInput(format!("Invalid VM definition: {}", err)))?;
+
+    if definition.version > CONFIG_VERSION {
+        return Err(KrunvmError::InvalidInput(format!(
+            "The VM definition was written by a newer version of krunvm (schema version {}), \
+             while this one only supports up to version {}",
+            definition.version, CONFIG_VERSION
+        )));
+    }
+
+    let vm = definition.vm;
+    if vm.image.is_empty() {
+        return Err(KrunvmError::InvalidInput(
+            "The VM definition doesn't include an image".to_string(),
+        ));
+    }
+
+    let name = match name {
+        Some(name) => name,
+        None if is_valid_vm_name(&vm.name) => free_name(cfg, &vm.name),
+        None => free_name(cfg, "imported"),
+    };
+
+    let opts = CreateOptions {
+        image: vm.image,
+        rootfs: None,
+        name: Some(name),
+        cpus: Some(vm.cpus),
+        mem: Some(vm.mem),
+        dns: Some(vm.dns).filter(|dns| !dns.is_empty()),
+        workdir: vm.workdir,
+        mapped_volumes: vm.mapped_volumes,
+        mapped_ports: vm.mapped_ports,
+        env: vm.env,
+        ulimits: vm.ulimits,
+        command: vm.command,
+        args: vm.args,
+        krun_log_level: vm.krun_log_level,
+        #[cfg(target_os = "macos")]
+        x86: false,
+    };
+
+    // Only x86 VMs on Aarch64 get their settings adjusted.
+    create_vm(cfg, backends, opts).map(|(name, _)| name)
+}